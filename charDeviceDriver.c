@@ -2,109 +2,2650 @@
 #include <linux/module.h>
 #include <linux/fs.h>
 #include <linux/slab.h>
+#include <linux/list.h>
+#include <linux/wait.h>
+#include <linux/poll.h>
+#include <linux/device.h>
+#include <linux/version.h>
+#include <linux/proc_fs.h>
+#include <linux/seq_file.h>
+#include <linux/debugfs.h>
+#include <linux/ktime.h>
+#include <linux/uio.h>
+#include <linux/delay.h>
+#include <linux/math64.h>
+#include <linux/mm.h>
 #include <asm/uaccess.h>
 #include <charDeviceDriver.h>
 
+// Compatibility with kernels before `iov_iter` gained `iter_iov`
+#if LINUX_VERSION_CODE < KERNEL_VERSION(6, 4, 0)
+#define iter_iov(iter) ((iter)->iov)
+#endif
+
+// Compatibility with kernels before `vm_flags` could only be changed through helpers
+#if LINUX_VERSION_CODE < KERNEL_VERSION(6, 3, 0)
+#define vm_flags_clear(vma, flags) ((vma)->vm_flags &= ~(flags))
+#endif
+
 // In case this affects tests
 MODULE_LICENSE("GPL");
 
-// Write a device driver for a character device which implements a simple way of message passing.
-// The kernel maintains a list of messages.
-// To limit memory usage, we impose a limit of 4KB = 4*1024 bytes for each message
-// and also impose a limit of the total number of messages stored in the kernel, which is 1000.
+// Write a device driver for a character device which implements a simple way of message passing.
+// The kernel maintains a list of messages.
+// To limit memory usage, we impose a limit of 4KB = 4*1024 bytes for each message
+// and also impose a limit of the total number of messages stored in the kernel, which is 1000.
+
+// Your device driver should perform the following operations:
+// - When the module is loaded, the device is created. An empty list of messages is created as well.
+// - Removing the module deallocates all messages, removes the list of messages and removes the device.
+// - Reading from the device returns one message, and removes this message from the kernel list.
+//   If the list of messages is empty, the reader returns -EAGAIN.
+// - Writing to the device stores the message in kernel space and adds it to the list if the message
+//   is below the maximum size, and the limit of the number of all messages stored in the kernel
+//   wouldn't be surpassed with this message. If the message is too big, -EINVAL is returned,
+//   and if the limit of the number of all messages was surpassed, -EBUSY is returned.
+// - The kernel module which implements this driver must be called charDeviceDriver.ko.
+
+// You need to ensure that your code deals with multiple attempts at reading and writing at the same time.
+// Moreover, your code should handle several read and write attempts concurrently.
+// Your critical sections should be as short as possible.
+// The reader should obtain the messages in a FIFO (first in first out) manner.
+
+#define MAX_STRING_LENGTH 4096               // Default for `max_string_length`
+#define MAX_STRING_LENGTH_CEILING (1024 * 1024) // Largest allowed `max_string_length`
+#define MAX_QUEUE_SIZE 1000                  // Default for `max_messages`
+#define MAX_QUEUE_SIZE_CEILING 65536         // Largest allowed `max_messages` or capacity
+
+static unsigned int max_string_length = MAX_STRING_LENGTH;
+module_param(max_string_length, uint, S_IRUGO);
+MODULE_PARM_DESC(max_string_length, "Maximum length of a message in bytes (default 4096)");
+
+static unsigned int max_messages = MAX_QUEUE_SIZE;
+module_param(max_messages, uint, S_IRUGO);
+MODULE_PARM_DESC(max_messages, "Initial maximum number of messages stored in each device (default 1000)");
+
+#define NUM_DEVICES_CEILING 256 // `register_chrdev` reserves 256 minor numbers
+
+static unsigned int num_devices = 1;
+module_param(num_devices, uint, S_IRUGO);
+MODULE_PARM_DESC(num_devices, "Number of independent devices, one per minor number (default 1)");
+
+typedef struct Message
+{
+    struct list_head list;
+    int length;
+    u8 priority;
+    u16 type;      // Set with `CHARDEV_IOC_WRITE_TYPED`, otherwise `CHARDEV_DEFAULT_TYPE`
+    u64 timestamp; // `ktime_get_ns` when enqueued, 0 unless the queue has timestamps enabled
+    u64 id;        // Increases by 1 with each message enqueued
+    u64 sequence;  // Like `id`, but only restarts from 0 when the statistics are reset
+    int partial;   // In stream mode, whether this is a copy of the start of a message whose rest is still queued
+    char string[];
+} Message;
+
+// Counters for `/proc/chardev/stats`, these are updated without taking the lock
+typedef struct Stats
+{
+    atomic64_t messages_written;
+    atomic64_t messages_read;
+    atomic64_t rejected_too_long; // Writes which failed with -EINVAL
+    atomic64_t rejected_busy;     // Writes which failed with -EBUSY
+    atomic64_t high_water;        // Most messages ever queued at once, only increased with the queue locked
+} Stats;
+
+// The state of one device
+typedef struct Queue
+{
+    struct mutex lock;
+    // Readers waiting for a message to be enqueued
+    wait_queue_head_t read_wait;
+    // Writers waiting for a message to be dequeued. Each waits for room for its own messages, so they don't wait
+    // exclusively, as the one woken may still not fit while another would
+    wait_queue_head_t write_wait;
+    struct list_head messages; // Oldest first
+    int size;
+    u64 bytes;       // Total length of `messages`
+    u64 byte_budget; // Maximum `bytes`, or 0 for no limit
+    int capacity;  // Maximum `size`, starts as `max_messages`
+    int overwrite; // Whether enqueueing onto a full queue evicts the oldest message
+    int mode;      // `CHARDEV_MODE_*`, the order messages are read in
+    int timestamp; // Whether enqueued messages record the time
+    int dedup;     // Whether a message identical to the newest one is dropped instead of enqueued
+    int strict;    // Whether messages of only whitespace are rejected
+    int frozen;    // Whether reads act as if the queue is empty, set with `CHARDEV_IOC_FREEZE`
+    int broadcast; // Whether every open file reads every message, instead of each message being read once
+    u64 next_id;   // `id` of the next message enqueued
+    u64 next_sequence;        // `sequence` of the next message enqueued, zeroed by `reset_stats`
+    char delimiter;           // Put between messages by `CHARDEV_IOC_READ_CONCAT`, starts as a line feed
+    int writes_blocked;       // Whether writes act as if the queue is full, set with `CHARDEV_IOC_BLOCK_WRITES`
+    int allow_empty;          // Whether empty writes enqueue an empty message, set with `CHARDEV_IOC_SET_ALLOW_EMPTY`
+    int full_errno;           // What writes to a full queue fail with, set with `CHARDEV_IOC_SET_FULL_ERRNO`
+    u64 generation;           // Increased each time messages are discarded unread in bulk, see `generation`
+    int drain_on_close;       // Whether the last close flushes the queue, set with `CHARDEV_IOC_SET_DRAIN_ON_CLOSE`
+    struct list_head handles; // Every open file of the device
+    int open_count;           // How many `handles` there are
+    int writers;              // How many of `handles` were opened for writing
+    char label[CHARDEV_LABEL_MAX];   // Set with `CHARDEV_IOC_SET_LABEL`, not null terminated
+    int label_length;                // Length of `label`
+    char prefix[CHARDEV_PREFIX_MAX]; // Set with `CHARDEV_IOC_SET_PREFIX`, every message written must start with it
+    int prefix_length;               // Length of `prefix`, 0 accepts any message
+    atomic_t inject;                 // `CHARDEV_INJECT_*` bits set with `CHARDEV_IOC_INJECT`, cleared by the next write
+    struct fasync_struct *async;     // Open files with `O_ASYNC`, which are sent `SIGIO` when a message is enqueued
+    unsigned int notify_watermark;   // `SIGIO` is only sent when at least this many messages are queued
+    struct Queue *tee;               // Set with `CHARDEV_IOC_SET_TEE`, gets a copy of every message enqueued, or NULL
+    Stats stats;
+    struct chardev_stats_page *stats_page; // Mapped read only by `mmap`, kept up to date by `publish_stats`
+} Queue;
+
+// The state of one open file
+typedef struct Handle
+{
+    Queue *queue;
+    struct list_head list; // In `queue->handles`
+    int reader;            // Whether the file was opened for reading, checked by every read, and by broadcast mode
+    int writer;            // Whether the file was opened for writing, checked by every write
+    u64 cursor;            // In broadcast mode, the lowest `id` this file hasn't read
+    int stream;            // Whether reads can consume part of a message
+    unsigned int rate;     // Maximum messages written per second, 0 for no limit
+    u64 rate_next_ns;      // When the rate limit's token bucket next has a token, as `ktime_get_ns`
+    // Counters for `CHARDEV_IOC_MY_STATS`, atomic as threads sharing the file may read and write at once
+    atomic64_t bytes_written;
+    atomic64_t bytes_read;
+    atomic64_t messages_written;
+    atomic64_t messages_read;
+} Handle;
+
+// Create a queue.
+Queue *create_queue(void)
+{
+    Queue *q = kzalloc(sizeof(Queue), GFP_KERNEL);
+    if (q == NULL)
+    {
+        printk(KERN_ALERT "Error: could not allocate memory for queue\n");
+        return NULL;
+    }
+    // A whole page, as that's the smallest amount which can be mapped
+    q->stats_page = (struct chardev_stats_page *)get_zeroed_page(GFP_KERNEL);
+    if (q->stats_page == NULL)
+    {
+        printk(KERN_ALERT "Error: could not allocate memory for queue\n");
+        kfree(q);
+        return NULL;
+    }
+    mutex_init(&q->lock);
+    init_waitqueue_head(&q->read_wait);
+    init_waitqueue_head(&q->write_wait);
+    INIT_LIST_HEAD(&q->messages);
+    INIT_LIST_HEAD(&q->handles);
+    q->size = 0;
+    q->capacity = max_messages;
+    q->overwrite = 0;
+    q->mode = CHARDEV_MODE_FIFO;
+    q->delimiter = '\n';
+    q->full_errno = EBUSY;
+    atomic_set(&q->inject, 0);
+    return q;
+}
+
+#ifdef CHARDEV_DEBUG
+// Whether the next `create_message` fails, set with `CHARDEV_IOC_FAIL_ALLOC`
+static atomic_t fail_alloc = ATOMIC_INIT(0);
+#endif
+
+// Create a message which can hold `length` bytes, which must be freed with `kvfree`.
+// Messages can be up to `MAX_STRING_LENGTH_CEILING` long, too large to always find contiguous pages for, so this falls
+// back to `vmalloc`, as do the buffers of that size used to copy messages out under the lock.
+Message *create_message(int length)
+{
+    Message *message;
+
+#ifdef CHARDEV_DEBUG
+    if (atomic_xchg(&fail_alloc, 0))
+        return NULL;
+#endif
+    message = kvmalloc(sizeof(Message) + length, GFP_KERNEL);
+    if (message == NULL)
+        return NULL;
+    message->length = length;
+    message->priority = CHARDEV_DEFAULT_PRIORITY;
+    message->type = CHARDEV_DEFAULT_TYPE;
+    message->timestamp = 0;
+    message->partial = 0;
+    return message;
+}
+
+// Returns the message which should be read next. The queue must be locked and not empty.
+static Message *next_message(Queue *queue)
+{
+    Message *message, *best;
+
+    switch (queue->mode)
+    {
+    case CHARDEV_MODE_LIFO:
+        return list_last_entry(&queue->messages, Message, list);
+    case CHARDEV_MODE_PRIORITY:
+        // The list is oldest first, so taking the first of the highest priority keeps equal priorities FIFO
+        best = list_first_entry(&queue->messages, Message, list);
+        list_for_each_entry(message, &queue->messages, list)
+        {
+            if (message->priority > best->priority)
+                best = message;
+        }
+        return best;
+    default:
+        return list_first_entry(&queue->messages, Message, list);
+    }
+}
+
+// Frees a list of messages.
+static void free_messages(struct list_head *messages)
+{
+    Message *message, *next;
+
+    list_for_each_entry_safe(message, next, messages, list)
+    {
+        kvfree(message);
+    }
+}
+
+// Copies the counters into the page user space can `mmap`. This is called after they change, and doesn't need the
+// lock, so readers of the page may briefly see counters which are inconsistent with each other.
+static void publish_stats(Queue *queue)
+{
+    struct chardev_stats_page *page = queue->stats_page;
+
+    WRITE_ONCE(page->queue_len, READ_ONCE(queue->size));
+    WRITE_ONCE(page->high_water, atomic64_read(&queue->stats.high_water));
+    WRITE_ONCE(page->bytes_queued, READ_ONCE(queue->bytes));
+    WRITE_ONCE(page->messages_written, atomic64_read(&queue->stats.messages_written));
+    WRITE_ONCE(page->messages_read, atomic64_read(&queue->stats.messages_read));
+}
+
+// Whether `message` has the same bytes as `previous`, which may be NULL.
+static int is_duplicate(Message *message, Message *previous)
+{
+    return previous != NULL && message->length == previous->length &&
+           memcmp(message->string, previous->string, message->length) == 0;
+}
+
+// Flags for `enqueue_messages`
+#define ENQUEUE_IF_EMPTY (1 << 0) // Fail with -EEXIST unless the queue is empty
+#define ENQUEUE_FRONT (1 << 1)    // Add the messages before the oldest, so in FIFO mode they're read next
+
+// Add a list of messages to the queue, either all of them or none if they don't fit.
+// On success the queue takes ownership of the messages and `messages` is left empty.
+// Messages are added in the order writers take the lock, so a write which returned before another started is
+// always ahead of it, unless it was added to the front.
+// With deduplication, messages identical to the one before them are freed instead of added. At the front this is the
+// oldest message rather than the newest.
+// Returns the number of messages added, which deduplication can make fewer than were given, -ENOSPC if they don't fit,
+// -EBUSY if writes are blocked, -EEXIST with `ENQUEUE_IF_EMPTY` if the queue isn't empty or -EINVAL with
+// `ENQUEUE_FRONT` in broadcast mode.
+// With `mirror` set, a copy of each message added is also enqueued on the queue's tee, if it has one.
+static int enqueue_messages(Queue *queue, struct list_head *messages, int flags, int mirror)
+{
+    Message *message, *next, *previous, *neighbour, *copy;
+    LIST_HEAD(evicted);
+    LIST_HEAD(duplicates);
+    LIST_HEAD(copies);
+    LIST_HEAD(added_messages);
+    Queue *tee;
+    int added = 0, copied = 0;
+    int notify, mirrored;
+    u64 added_bytes = 0;
+
+    mutex_lock(&queue->lock);
+
+    if ((flags & ENQUEUE_IF_EMPTY) && queue->size != 0)
+    {
+        mutex_unlock(&queue->lock);
+        return -EEXIST;
+    }
+    // Each open file reads messages in the order of their IDs, which the front would be out of
+    if ((flags & ENQUEUE_FRONT) && queue->broadcast)
+    {
+        mutex_unlock(&queue->lock);
+        return -EINVAL;
+    }
+    // Blocked writes fail even when overwriting, so the queue can only drain
+    if (queue->writes_blocked)
+    {
+        mutex_unlock(&queue->lock);
+        return -EBUSY;
+    }
+
+    // The message next to where they're added. Evicting may remove it, but it isn't freed until after unlocking so can
+    // still be compared against
+    if (list_empty(&queue->messages))
+        neighbour = NULL;
+    else if (flags & ENQUEUE_FRONT)
+        neighbour = list_first_entry(&queue->messages, Message, list);
+    else
+        neighbour = list_last_entry(&queue->messages, Message, list);
+    previous = neighbour;
+    list_for_each_entry(message, messages, list)
+    {
+        if (queue->dedup && is_duplicate(message, previous))
+            continue;
+        previous = message;
+        added++;
+        added_bytes += message->length;
+    }
+
+    if (queue->size + added > queue->capacity)
+    {
+        // printk(KERN_INFO "[Queue] Queue is full\n");
+        if (!queue->overwrite || added > queue->capacity)
+        {
+            mutex_unlock(&queue->lock);
+            return -ENOSPC;
+        }
+        // Make room by evicting the oldest messages
+        while (queue->size + added > queue->capacity)
+        {
+            list_move_tail(queue->messages.next, &evicted);
+            queue->size--;
+            queue->bytes -= list_last_entry(&evicted, Message, list)->length;
+        }
+    }
+    // Overwriting only evicts to make room in the capacity, going over the byte budget always fails
+    if (queue->byte_budget != 0 && queue->bytes + added_bytes > queue->byte_budget)
+    {
+        // Anything evicted was the oldest, so it goes back at the front
+        list_for_each_entry(message, &evicted, list)
+        {
+            queue->size++;
+            queue->bytes += message->length;
+        }
+        list_splice(&evicted, &queue->messages);
+        mutex_unlock(&queue->lock);
+        return -ENOSPC;
+    }
+
+    tee = mirror ? queue->tee : NULL;
+    previous = neighbour;
+    list_for_each_entry_safe(message, next, messages, list)
+    {
+        if (queue->dedup && is_duplicate(message, previous))
+        {
+            list_move_tail(&message->list, &duplicates);
+            continue;
+        }
+        // Mirroring is best effort, so a copy which can't be allocated is skipped
+        copy = tee == NULL ? NULL : create_message(message->length);
+        if (copy != NULL)
+        {
+            copy->priority = message->priority;
+            copy->type = message->type;
+            memcpy(copy->string, message->string, message->length);
+            list_add_tail(&copy->list, &copies);
+            copied++;
+        }
+        // Taken under the lock so timestamps increase in the order messages were enqueued
+        if (queue->timestamp)
+            message->timestamp = ktime_get_ns();
+        message->id = queue->next_id++;
+        message->sequence = queue->next_sequence++;
+        list_move_tail(&message->list, &added_messages);
+        previous = message;
+    }
+    if (flags & ENQUEUE_FRONT)
+        list_splice(&added_messages, &queue->messages);
+    else
+        list_splice_tail(&added_messages, &queue->messages);
+    queue->size += added;
+    queue->bytes += added_bytes;
+    if (queue->size > atomic64_read(&queue->stats.high_water))
+        atomic64_set(&queue->stats.high_water, queue->size);
+    notify = queue->size >= queue->notify_watermark;
+
+    mutex_unlock(&queue->lock);
+
+    free_messages(&evicted);
+    free_messages(&duplicates);
+    wake_up_interruptible(&queue->read_wait);
+    if (notify)
+        kill_fasync(&queue->async, SIGIO, POLL_IN);
+
+    // Copies aren't mirrored again, so tees pointing at each other can't loop
+    if (copied > 0)
+    {
+        // The tee may deduplicate some copies, so only those it added count as written to it
+        mirrored = enqueue_messages(tee, &copies, 0, 0);
+        if (mirrored >= 0)
+        {
+            atomic64_add(mirrored, &tee->stats.messages_written);
+            publish_stats(tee);
+        }
+        else
+        {
+            // The tee is full, which doesn't affect this queue
+            free_messages(&copies);
+        }
+    }
+
+    return added;
+}
+
+// Add a list of messages to the queue, see `enqueue_messages`.
+int enqueue_all(Queue *queue, struct list_head *messages, int flags)
+{
+    return enqueue_messages(queue, messages, flags, 1);
+}
+
+// Add a message to the queue, with `ENQUEUE_*` flags. On success the queue takes ownership of the message.
+// Returns 1 if it was added or 0 if deduplication freed it, otherwise an error, see `enqueue_messages`.
+int enqueue(Queue *queue, Message *message, int flags)
+{
+    LIST_HEAD(messages);
+
+    list_add_tail(&message->list, &messages);
+    return enqueue_all(queue, &messages, flags);
+}
+
+// In broadcast mode, moves the messages every file open for reading has read into `reclaimed` for the caller to free.
+// Files opened with `O_WRONLY` never read, so would otherwise keep every message forever.
+// The queue must be locked. Returns the number of messages reclaimed.
+static int reclaim_read_messages(Queue *queue, struct list_head *reclaimed)
+{
+    Handle *handle;
+    Message *message, *next;
+    u64 oldest_cursor = queue->next_id;
+    int count = 0;
+
+    list_for_each_entry(handle, &queue->handles, list)
+    {
+        if (handle->reader)
+            oldest_cursor = min(oldest_cursor, handle->cursor);
+    }
+    list_for_each_entry_safe(message, next, &queue->messages, list)
+    {
+        if (message->id >= oldest_cursor)
+            break;
+        list_move_tail(&message->list, reclaimed);
+        queue->size--;
+        queue->bytes -= message->length;
+        count++;
+    }
+
+    return count;
+}
+
+// In broadcast mode, returns a copy of the oldest message `handle` hasn't read, unless it is longer than
+// `max_length`. The queue must be locked. Returns the same errors as `dequeue`.
+static Message *broadcast_next(Queue *queue, Handle *handle, size_t max_length)
+{
+    Message *message, *copy;
+
+    list_for_each_entry(message, &queue->messages, list)
+    {
+        if (message->id < handle->cursor)
+            continue;
+        if (message->length > max_length)
+            return ERR_PTR(-EMSGSIZE);
+
+        copy = create_message(message->length);
+        if (copy == NULL)
+            return ERR_PTR(-ENOMEM);
+        memcpy(copy->string, message->string, message->length);
+        copy->priority = message->priority;
+        copy->type = message->type;
+        copy->timestamp = message->timestamp;
+        copy->id = message->id;
+        copy->sequence = message->sequence;
+        handle->cursor = message->id + 1;
+        return copy;
+    }
+
+    // Any unread messages were evicted or flushed, so catch up to stop waiting for them
+    handle->cursor = queue->next_id;
+    return ERR_PTR(-EAGAIN);
+}
+
+// In stream mode, returns a copy of the first `max_length` bytes of `message` and trims them from the queued message,
+// so whichever file reads it next, in any mode, gets the rest. If the whole message fits, instead returns NULL so it
+// can be dequeued. The queue must be locked.
+static Message *stream_chunk(Queue *queue, Message *message, size_t max_length)
+{
+    Message *chunk;
+
+    if (message->length <= max_length)
+        return NULL;
+
+    chunk = create_message(max_length);
+    if (chunk == NULL)
+        return ERR_PTR(-ENOMEM);
+    memcpy(chunk->string, message->string, max_length);
+    chunk->sequence = message->sequence;
+    chunk->partial = 1;
+    // The allocation isn't shrunk, but the bytes trimmed no longer count towards the byte budget
+    message->length -= max_length;
+    memmove(message->string, message->string + max_length, message->length);
+    queue->bytes -= max_length;
+    return chunk;
+}
+
+// Removes a message from the queue, unless it is longer than `max_length`. The caller must free the message.
+// In stream mode a message longer than `max_length` instead has its first `max_length` bytes removed and returned,
+// and the message is only removed with its last part.
+// In broadcast mode this is a copy of the next message `handle` hasn't read, which is only removed from the queue
+// once every open file has read it.
+// Returns ERR_PTR(-EAGAIN) if the queue is empty or frozen, or ERR_PTR(-EMSGSIZE) if the message is too long.
+Message *dequeue(Queue *queue, Handle *handle, size_t max_length)
+{
+    Message *message, *chunk;
+    LIST_HEAD(reclaimed);
+
+    mutex_lock(&queue->lock);
+
+    // A frozen queue reads as empty, whatever it holds
+    if (queue->frozen)
+    {
+        mutex_unlock(&queue->lock);
+        return ERR_PTR(-EAGAIN);
+    }
+    if (queue->broadcast)
+    {
+        message = broadcast_next(queue, handle, max_length);
+        if (IS_ERR(message) || reclaim_read_messages(queue, &reclaimed) == 0)
+        {
+            mutex_unlock(&queue->lock);
+            return message;
+        }
+        mutex_unlock(&queue->lock);
+
+        free_messages(&reclaimed);
+        wake_up_interruptible_all(&queue->write_wait);
+        return message;
+    }
+
+    if (queue->size == 0)
+    {
+        // printk(KERN_INFO "[Queue] Queue is empty\n");
+        mutex_unlock(&queue->lock);
+        return ERR_PTR(-EAGAIN);
+    }
+
+    message = next_message(queue);
+    if (handle->stream)
+    {
+        chunk = stream_chunk(queue, message, max_length);
+        if (chunk != NULL)
+        {
+            mutex_unlock(&queue->lock);
+            // Trimming the message may have made room in the byte budget
+            if (!IS_ERR(chunk))
+                wake_up_interruptible(&queue->write_wait);
+            return chunk;
+        }
+    }
+    else if (message->length > max_length)
+    {
+        mutex_unlock(&queue->lock);
+        return ERR_PTR(-EMSGSIZE);
+    }
+    list_del(&message->list);
+    queue->size--;
+    queue->bytes -= message->length;
+
+    mutex_unlock(&queue->lock);
+
+    wake_up_interruptible(&queue->write_wait);
+
+    return message;
+}
+
+// Removes the oldest message of the given type, unless it is longer than `max_length`. The caller must free it.
+// Returns ERR_PTR(-EAGAIN) if there's no message of the type or the queue is frozen, ERR_PTR(-EMSGSIZE) if the
+// message is too long or ERR_PTR(-EINVAL) in broadcast mode, where each open file reads every message anyway.
+Message *dequeue_typed(Queue *queue, u16 type, size_t max_length)
+{
+    Message *message;
+
+    mutex_lock(&queue->lock);
+
+    if (queue->broadcast)
+    {
+        mutex_unlock(&queue->lock);
+        return ERR_PTR(-EINVAL);
+    }
+    if (!queue->frozen)
+    {
+        list_for_each_entry(message, &queue->messages, list)
+        {
+            if (message->type != type)
+                continue;
+            if (message->length > max_length)
+            {
+                mutex_unlock(&queue->lock);
+                return ERR_PTR(-EMSGSIZE);
+            }
+            list_del(&message->list);
+            queue->size--;
+            queue->bytes -= message->length;
+
+            mutex_unlock(&queue->lock);
+
+            wake_up_interruptible(&queue->write_wait);
+            return message;
+        }
+    }
+
+    mutex_unlock(&queue->lock);
+
+    return ERR_PTR(-EAGAIN);
+}
+
+// Removes the newest message, unless it is longer than `max_length`. The caller must free it.
+// Returns ERR_PTR(-EAGAIN) if the queue is empty or frozen, ERR_PTR(-EMSGSIZE) if the message is too long or
+// ERR_PTR(-EINVAL) in broadcast mode, like `dequeue_typed`.
+Message *dequeue_newest(Queue *queue, size_t max_length)
+{
+    Message *message;
+
+    mutex_lock(&queue->lock);
+
+    if (queue->broadcast)
+    {
+        mutex_unlock(&queue->lock);
+        return ERR_PTR(-EINVAL);
+    }
+    if (queue->size == 0 || queue->frozen)
+    {
+        mutex_unlock(&queue->lock);
+        return ERR_PTR(-EAGAIN);
+    }
+    message = list_last_entry(&queue->messages, Message, list);
+    if (message->length > max_length)
+    {
+        mutex_unlock(&queue->lock);
+        return ERR_PTR(-EMSGSIZE);
+    }
+    list_del(&message->list);
+    queue->size--;
+    queue->bytes -= message->length;
+
+    mutex_unlock(&queue->lock);
+
+    wake_up_interruptible(&queue->write_wait);
+
+    return message;
+}
+
+// Removes the message which would be read next, only if it is exactly `length` bytes of `expected`. The caller must
+// free it. Returns ERR_PTR(-EAGAIN) if the queue is empty or frozen or the message is different, leaving the queue
+// unchanged, or ERR_PTR(-EINVAL) in broadcast mode, like `dequeue_typed`.
+Message *dequeue_if_equal(Queue *queue, const char *expected, size_t length)
+{
+    Message *message;
+
+    mutex_lock(&queue->lock);
+
+    if (queue->broadcast)
+    {
+        mutex_unlock(&queue->lock);
+        return ERR_PTR(-EINVAL);
+    }
+    if (queue->size == 0 || queue->frozen)
+    {
+        mutex_unlock(&queue->lock);
+        return ERR_PTR(-EAGAIN);
+    }
+    message = next_message(queue);
+    if (message->length != length || memcmp(message->string, expected, length) != 0)
+    {
+        mutex_unlock(&queue->lock);
+        return ERR_PTR(-EAGAIN);
+    }
+    list_del(&message->list);
+    queue->size--;
+    queue->bytes -= message->length;
+
+    mutex_unlock(&queue->lock);
+
+    wake_up_interruptible(&queue->write_wait);
+
+    return message;
+}
+
+// Appends `length` bytes of `suffix` to every message in the queue, either all of them or none.
+// Returns the number of messages changed, -EINVAL if any would be longer than `max_string_length` or -EBUSY if they
+// would go over the byte budget.
+int append_all(Queue *queue, const char *suffix, int length)
+{
+    Message *message, *copy;
+    LIST_HEAD(copies);
+    LIST_HEAD(replaced);
+    int count;
+
+    mutex_lock(&queue->lock);
+
+    list_for_each_entry(message, &queue->messages, list)
+    {
+        if (message->length + length > max_string_length)
+        {
+            mutex_unlock(&queue->lock);
+            return -EINVAL;
+        }
+    }
+    if (queue->byte_budget != 0 && queue->bytes + (u64)queue->size * length > queue->byte_budget)
+    {
+        mutex_unlock(&queue->lock);
+        return -EBUSY;
+    }
+    // Messages can't grow in place, so each is replaced by a longer copy once every copy has been allocated
+    list_for_each_entry(message, &queue->messages, list)
+    {
+        copy = create_message(message->length + length);
+        if (copy == NULL)
+        {
+            mutex_unlock(&queue->lock);
+            free_messages(&copies);
+            return -ENOMEM;
+        }
+        memcpy(copy->string, message->string, message->length);
+        memcpy(copy->string + message->length, suffix, length);
+        copy->priority = message->priority;
+        copy->type = message->type;
+        copy->timestamp = message->timestamp;
+        copy->id = message->id;
+        copy->sequence = message->sequence;
+        list_add_tail(&copy->list, &copies);
+    }
+    list_splice_init(&queue->messages, &replaced);
+    list_splice_init(&copies, &queue->messages);
+    count = queue->size;
+    queue->bytes += (u64)count * length;
+
+    mutex_unlock(&queue->lock);
+
+    free_messages(&replaced);
+    publish_stats(queue);
+
+    return count;
+}
+
+// Copies the message which would be read next without removing it, unless it is longer than `max_length`.
+// Returns the length of the message, -EAGAIN if the queue is empty, -EMSGSIZE if the message is too long or -EINVAL in
+// broadcast mode, where the next message depends on which open file reads it.
+int peek(Queue *queue, char *string, size_t max_length)
+{
+    Message *message;
+    int length;
+
+    mutex_lock(&queue->lock);
+
+    if (queue->broadcast)
+    {
+        mutex_unlock(&queue->lock);
+        return -EINVAL;
+    }
+    if (queue->size == 0)
+    {
+        mutex_unlock(&queue->lock);
+        return -EAGAIN;
+    }
+
+    message = next_message(queue);
+    length = message->length;
+    if (length > max_length)
+    {
+        mutex_unlock(&queue->lock);
+        return -EMSGSIZE;
+    }
+    memcpy(string, message->string, length);
+
+    mutex_unlock(&queue->lock);
+
+    return length;
+}
+
+// Copies the oldest message and moves it to the end of the queue, as if it had been read and written again, unless
+// it is longer than `max_length`.
+// Returns the length of the message, -EAGAIN if the queue is empty or frozen, -EMSGSIZE if the message is too long or
+// -EINVAL in broadcast mode, where the new id would have files which already read it read it again.
+int rotate(Queue *queue, char *string, size_t max_length)
+{
+    Message *message;
+    int length;
+
+    mutex_lock(&queue->lock);
+
+    if (queue->broadcast)
+    {
+        mutex_unlock(&queue->lock);
+        return -EINVAL;
+    }
+    if (queue->size == 0 || queue->frozen)
+    {
+        mutex_unlock(&queue->lock);
+        return -EAGAIN;
+    }
+
+    message = list_first_entry(&queue->messages, Message, list);
+    length = message->length;
+    if (length > max_length)
+    {
+        mutex_unlock(&queue->lock);
+        return -EMSGSIZE;
+    }
+    memcpy(string, message->string, length);
+    // Renumbered like a new message, so ids and sequence numbers still increase through the list
+    if (queue->timestamp)
+        message->timestamp = ktime_get_ns();
+    message->id = queue->next_id++;
+    message->sequence = queue->next_sequence++;
+    list_move_tail(&message->list, &queue->messages);
+
+    mutex_unlock(&queue->lock);
+
+    return length;
+}
+
+// Copies the message `index` places from the oldest without removing it, unless it is longer than `max_length`.
+// Returns the length of the message, -ERANGE if there are `index` or fewer messages, -EMSGSIZE if it is too long or
+// -EINVAL in broadcast mode, like `peek`, as the queue holds messages some open files have already read.
+int peek_at(Queue *queue, unsigned int index, char *string, size_t max_length)
+{
+    Message *message;
+    int length = -ERANGE;
+
+    mutex_lock(&queue->lock);
+
+    if (queue->broadcast)
+    {
+        mutex_unlock(&queue->lock);
+        return -EINVAL;
+    }
+    list_for_each_entry(message, &queue->messages, list)
+    {
+        if (index-- != 0)
+            continue;
+        length = message->length;
+        if (length > max_length)
+            length = -EMSGSIZE;
+        else
+            memcpy(string, message->string, length);
+        break;
+    }
+
+    mutex_unlock(&queue->lock);
+
+    return length;
+}
+
+// Returns the length of the message which would be read next, -EAGAIN if the queue is empty or -EINVAL in broadcast
+// mode, like `peek`.
+int peek_length(Queue *queue)
+{
+    int length = -EAGAIN;
+
+    mutex_lock(&queue->lock);
+    if (queue->broadcast)
+        length = -EINVAL;
+    else if (queue->size != 0)
+        length = next_message(queue)->length;
+    mutex_unlock(&queue->lock);
+
+    return length;
+}
+
+// Removes every message from the queue into `messages`, oldest first, unless their total size, with `overhead` extra
+// bytes for each message, is more than `max_length`. The caller must free the messages.
+// Returns the number of messages, -EMSGSIZE if they don't fit, -EAGAIN if the queue is frozen, like `dequeue`, or
+// -EINVAL in broadcast mode, where it would remove messages other open files haven't read.
+int drain(Queue *queue, struct list_head *messages, size_t max_length, size_t overhead)
+{
+    Message *message;
+    size_t total = 0;
+    int count;
+
+    mutex_lock(&queue->lock);
+
+    if (queue->broadcast)
+    {
+        mutex_unlock(&queue->lock);
+        return -EINVAL;
+    }
+    // A frozen queue reads as empty, whatever it holds
+    if (queue->frozen)
+    {
+        mutex_unlock(&queue->lock);
+        return -EAGAIN;
+    }
+    list_for_each_entry(message, &queue->messages, list)
+    {
+        total += overhead + message->length;
+    }
+    if (total > max_length)
+    {
+        mutex_unlock(&queue->lock);
+        return -EMSGSIZE;
+    }
+    list_splice_init(&queue->messages, messages);
+    count = queue->size;
+    queue->size = 0;
+    queue->bytes = 0;
+
+    mutex_unlock(&queue->lock);
+
+    wake_up_interruptible_all(&queue->write_wait);
+
+    return count;
+}
+
+// Copies every message into `*records`, each as a `__u32` length followed by the message, unless that's longer than
+// `max_length`. The caller must `kvfree` the records, which are NULL if the queue is empty.
+// Returns the length of the records, -EMSGSIZE if they are too long or -EINVAL in broadcast mode, like `peek_at`.
+long snapshot(Queue *queue, char **records, size_t max_length)
+{
+    Message *message;
+    size_t total = 0;
+    char *data;
+    __u32 length;
+
+    *records = NULL;
+
+    mutex_lock(&queue->lock);
+
+    if (queue->broadcast)
+    {
+        mutex_unlock(&queue->lock);
+        return -EINVAL;
+    }
+    list_for_each_entry(message, &queue->messages, list)
+    {
+        total += sizeof(length) + message->length;
+    }
+    if (total > max_length)
+    {
+        mutex_unlock(&queue->lock);
+        return -EMSGSIZE;
+    }
+    if (total == 0)
+    {
+        mutex_unlock(&queue->lock);
+        return 0;
+    }
+    // Copied under the lock so the snapshot is consistent, and can't go straight to user space as that may fault
+    data = kvmalloc(total, GFP_KERNEL);
+    if (data == NULL)
+    {
+        mutex_unlock(&queue->lock);
+        return -ENOMEM;
+    }
+    *records = data;
+    list_for_each_entry(message, &queue->messages, list)
+    {
+        length = message->length;
+        memcpy(data, &length, sizeof(length));
+        memcpy(data + sizeof(length), message->string, length);
+        data += sizeof(length) + length;
+    }
+
+    mutex_unlock(&queue->lock);
+
+    return total;
+}
+
+// Removes and frees every message in the queue.
+void flush_queue(Queue *queue)
+{
+    LIST_HEAD(messages);
+
+    mutex_lock(&queue->lock);
+
+    list_splice_init(&queue->messages, &messages);
+    queue->size = 0;
+    queue->bytes = 0;
+    queue->generation++;
+
+    mutex_unlock(&queue->lock);
+
+    publish_stats(queue);
+    wake_up_interruptible_all(&queue->write_wait);
+
+    // Free outside the lock to keep the critical section short
+    free_messages(&messages);
+}
+
+// Replaces every message in the queue with `count` messages of `bytes` in total, which are added in order.
+// On success the queue takes ownership of the messages and `messages` is left empty.
+// Unlike `enqueue_all` this never evicts or deduplicates, and ignores `CHARDEV_IOC_BLOCK_WRITES`.
+// Returns `count`, or -EBUSY if the messages don't fit in the capacity or byte budget.
+int replace_messages(Queue *queue, struct list_head *messages, int count, u64 bytes)
+{
+    Message *message;
+    LIST_HEAD(replaced);
+
+    mutex_lock(&queue->lock);
+
+    if (count > queue->capacity || (queue->byte_budget != 0 && bytes > queue->byte_budget))
+    {
+        mutex_unlock(&queue->lock);
+        return -EBUSY;
+    }
+    list_splice_init(&queue->messages, &replaced);
+    queue->generation++;
+    list_for_each_entry(message, messages, list)
+    {
+        if (queue->timestamp)
+            message->timestamp = ktime_get_ns();
+        message->id = queue->next_id++;
+        message->sequence = queue->next_sequence++;
+    }
+    list_splice_init(messages, &queue->messages);
+    queue->size = count;
+    queue->bytes = bytes;
+    if (queue->size > atomic64_read(&queue->stats.high_water))
+        atomic64_set(&queue->stats.high_water, queue->size);
+
+    mutex_unlock(&queue->lock);
+
+    free_messages(&replaced);
+    publish_stats(queue);
+    wake_up_interruptible_all(&queue->write_wait);
+    if (count > 0)
+        wake_up_interruptible(&queue->read_wait);
+
+    return count;
+}
+
+// Removes and frees up to `n` of the oldest, or newest, messages in the queue. Returns the number removed.
+static int drop_messages(Queue *queue, unsigned int n, int newest)
+{
+    LIST_HEAD(dropped);
+    int count = 0;
+
+    mutex_lock(&queue->lock);
+    while (count < n && queue->size > 0)
+    {
+        // The list is doubly linked, so either end can be removed in constant time
+        list_move_tail(newest ? queue->messages.prev : queue->messages.next, &dropped);
+        queue->size--;
+        queue->bytes -= list_last_entry(&dropped, Message, list)->length;
+        count++;
+    }
+    if (count > 0)
+        queue->generation++;
+    mutex_unlock(&queue->lock);
+
+    free_messages(&dropped);
+    if (count > 0)
+    {
+        publish_stats(queue);
+        wake_up_interruptible_all(&queue->write_wait);
+    }
+
+    return count;
+}
+
+// Removes and frees up to `n` of the oldest messages in the queue. Returns the number removed.
+int drop_oldest(Queue *queue, unsigned int n)
+{
+    return drop_messages(queue, n, 0);
+}
+
+// Removes and frees up to `n` of the newest messages in the queue. Returns the number removed.
+int drop_newest(Queue *queue, unsigned int n)
+{
+    return drop_messages(queue, n, 1);
+}
+
+// Returns the number of strings in the queue.
+int queue_length(Queue *queue)
+{
+    int size;
+
+    mutex_lock(&queue->lock);
+    size = queue->size;
+    mutex_unlock(&queue->lock);
+
+    return size;
+}
+
+// Returns how many more messages can be enqueued before the queue is full.
+int free_slots(Queue *queue)
+{
+    int slots;
+
+    mutex_lock(&queue->lock);
+    slots = queue->capacity - queue->size;
+    mutex_unlock(&queue->lock);
+
+    return slots;
+}
+
+// Returns the maximum number of messages in the queue.
+int queue_capacity(Queue *queue)
+{
+    return READ_ONCE(queue->capacity);
+}
+
+// Sets the maximum number of messages in the queue.
+// Returns -EINVAL if `capacity` is out of range or -EBUSY if more messages than that are queued.
+int set_capacity(Queue *queue, unsigned int capacity)
+{
+    if (capacity < 1 || capacity > MAX_QUEUE_SIZE_CEILING)
+        return -EINVAL;
+
+    mutex_lock(&queue->lock);
+
+    // Shrinking never drops messages
+    if (capacity < queue->size)
+    {
+        mutex_unlock(&queue->lock);
+        return -EBUSY;
+    }
+    queue->capacity = capacity;
+
+    mutex_unlock(&queue->lock);
+
+    // Growing may have made room for any waiting writers
+    wake_up_interruptible_all(&queue->write_wait);
+
+    return 0;
+}
+
+// Sets `*age` to how many nanoseconds ago the oldest message was enqueued.
+// Returns -EAGAIN if the queue is empty, or -ENODATA if timestamps were off when the oldest message was enqueued.
+int head_age(Queue *queue, u64 *age)
+{
+    Message *message;
+    int result = 0;
+
+    mutex_lock(&queue->lock);
+
+    if (queue->size == 0)
+        result = -EAGAIN;
+    else
+    {
+        message = list_first_entry(&queue->messages, Message, list);
+        if (message->timestamp == 0)
+            result = -ENODATA;
+        else
+            *age = ktime_get_ns() - message->timestamp;
+    }
+
+    mutex_unlock(&queue->lock);
+
+    return result;
+}
+
+// Fills `status` with the state of the queue, all read under the lock so they're consistent with each other.
+void get_status(Queue *queue, struct chardev_status *status)
+{
+    mutex_lock(&queue->lock);
+    status->len = queue->size;
+    status->free = queue->capacity - queue->size;
+    status->bytes = queue->bytes;
+    status->max_messages = queue->capacity;
+    mutex_unlock(&queue->lock);
+
+    status->max_len = max_string_length;
+}
+
+// Fills `range` with the lengths of the shortest and longest messages in the queue, or zeros if it's empty.
+void size_range(Queue *queue, struct chardev_size_range *range)
+{
+    Message *message;
+
+    range->min = 0;
+    range->max = 0;
+
+    mutex_lock(&queue->lock);
+
+    if (!list_empty(&queue->messages))
+    {
+        message = list_first_entry(&queue->messages, Message, list);
+        range->min = message->length;
+        range->max = message->length;
+    }
+    list_for_each_entry(message, &queue->messages, list)
+    {
+        if (message->length < range->min)
+            range->min = message->length;
+        if (message->length > range->max)
+            range->max = message->length;
+    }
+
+    mutex_unlock(&queue->lock);
+}
+
+// Returns how many times the queue has been cleared, by flushing, loading a template or the last close with
+// `CHARDEV_IOC_SET_DRAIN_ON_CLOSE`, each of which counts even if the queue was already empty, or had messages dropped,
+// which only counts if any were. Messages removed by being read, even all at once, or moved to another device don't
+// count, as they aren't lost.
+u64 generation(Queue *queue)
+{
+    u64 generation;
+
+    mutex_lock(&queue->lock);
+    generation = queue->generation;
+    mutex_unlock(&queue->lock);
+
+    return generation;
+}
+
+// Returns the total length of the messages in the queue.
+u64 bytes_queued(Queue *queue)
+{
+    u64 bytes;
+
+    mutex_lock(&queue->lock);
+    bytes = queue->bytes;
+    mutex_unlock(&queue->lock);
+
+    return bytes;
+}
+
+// Sets the maximum total length of the messages in the queue, or 0 for no limit.
+// Returns -EBUSY if more than `byte_budget` bytes are already queued.
+int set_byte_budget(Queue *queue, u64 byte_budget)
+{
+    mutex_lock(&queue->lock);
+
+    // As with the capacity, lowering the budget never drops messages
+    if (byte_budget != 0 && byte_budget < queue->bytes)
+    {
+        mutex_unlock(&queue->lock);
+        return -EBUSY;
+    }
+    queue->byte_budget = byte_budget;
+
+    mutex_unlock(&queue->lock);
+
+    wake_up_interruptible_all(&queue->write_wait);
+
+    return 0;
+}
+
+// Zeroes the statistics and sequence numbers, the lock is taken so the high water mark isn't raised at the same time.
+void reset_stats(Queue *queue)
+{
+    Stats *stats = &queue->stats;
+
+    mutex_lock(&queue->lock);
+    atomic64_set(&stats->messages_written, 0);
+    atomic64_set(&stats->messages_read, 0);
+    atomic64_set(&stats->rejected_too_long, 0);
+    atomic64_set(&stats->rejected_busy, 0);
+    atomic64_set(&stats->high_water, 0);
+    queue->next_sequence = 0;
+    mutex_unlock(&queue->lock);
+
+    publish_stats(queue);
+}
+
+// Sets whether enqueueing onto a full queue evicts the oldest message instead of failing.
+void set_overwrite(Queue *queue, int overwrite)
+{
+    mutex_lock(&queue->lock);
+    queue->overwrite = overwrite;
+    mutex_unlock(&queue->lock);
+}
+
+// Sets whether writing a message of only whitespace fails with -EINVAL.
+void set_strict(Queue *queue, int strict)
+{
+    mutex_lock(&queue->lock);
+    queue->strict = strict;
+    mutex_unlock(&queue->lock);
+}
+
+// Sets whether the queue is flushed when its last open file is closed.
+void set_drain_on_close(Queue *queue, int drain_on_close)
+{
+    mutex_lock(&queue->lock);
+    queue->drain_on_close = drain_on_close;
+    mutex_unlock(&queue->lock);
+}
+
+// Sets whether empty writes enqueue an empty message.
+void set_allow_empty(Queue *queue, int allow_empty)
+{
+    mutex_lock(&queue->lock);
+    queue->allow_empty = allow_empty;
+    mutex_unlock(&queue->lock);
+}
+
+// Sets the label of the queue, which must be at most `CHARDEV_LABEL_MAX` bytes.
+void set_label(Queue *queue, const char *label, int length)
+{
+    mutex_lock(&queue->lock);
+    memcpy(queue->label, label, length);
+    queue->label_length = length;
+    mutex_unlock(&queue->lock);
+}
+
+// Copies the label of the queue into `label`, which must hold `CHARDEV_LABEL_MAX` bytes, returning its length.
+int get_label(Queue *queue, char *label)
+{
+    int length;
+
+    mutex_lock(&queue->lock);
+    length = queue->label_length;
+    memcpy(label, queue->label, length);
+    mutex_unlock(&queue->lock);
+
+    return length;
+}
+
+// Sets the prefix every message written to the queue must start with, which must be at most `CHARDEV_PREFIX_MAX`
+// bytes. An empty prefix accepts any message.
+void set_prefix(Queue *queue, const char *prefix, int length)
+{
+    mutex_lock(&queue->lock);
+    memcpy(queue->prefix, prefix, length);
+    queue->prefix_length = length;
+    mutex_unlock(&queue->lock);
+}
+
+// Whether a message starts with the prefix of the queue.
+static int has_prefix(Queue *queue, Message *message)
+{
+    int result;
+
+    mutex_lock(&queue->lock);
+    result = message->length >= queue->prefix_length &&
+             memcmp(message->string, queue->prefix, queue->prefix_length) == 0;
+    mutex_unlock(&queue->lock);
+
+    return result;
+}
+
+// Makes the next write to the queue fail with the error of one of the `CHARDEV_INJECT_*` bits in `errors`.
+// Returns -EINVAL if `errors` has an unknown bit.
+int inject_errors(Queue *queue, unsigned int errors)
+{
+    if (errors & ~(CHARDEV_INJECT_EBUSY | CHARDEV_INJECT_EINVAL | CHARDEV_INJECT_ENOMEM))
+        return -EINVAL;
+    atomic_set(&queue->inject, errors);
+    return 0;
+}
+
+// Clears the injected errors of the queue, returning the error the current write should fail with, or 0 if none.
+static int take_injected_error(Queue *queue)
+{
+    int errors = atomic_xchg(&queue->inject, 0);
+
+    if (errors & CHARDEV_INJECT_EBUSY)
+        return -EBUSY;
+    if (errors & CHARDEV_INJECT_EINVAL)
+        return -EINVAL;
+    if (errors & CHARDEV_INJECT_ENOMEM)
+        return -ENOMEM;
+    return 0;
+}
+
+// Whether a message is only ASCII spaces, tabs, carriage returns and line feeds.
+static int is_blank(Message *message)
+{
+    int i;
+
+    for (i = 0; i < message->length; i++)
+    {
+        switch (message->string[i])
+        {
+        case ' ':
+        case '\t':
+        case '\r':
+        case '\n':
+            break;
+        default:
+            return 0;
+        }
+    }
+
+    return 1;
+}
+
+// Sets whether reads act as if the queue is empty. Writes are still accepted while frozen.
+void set_frozen(Queue *queue, int frozen)
+{
+    mutex_lock(&queue->lock);
+    queue->frozen = frozen;
+    mutex_unlock(&queue->lock);
+
+    // Readers waiting while frozen may now have a message
+    if (!frozen)
+        wake_up_interruptible_all(&queue->read_wait);
+}
+
+// Sets whether writes fail with -EBUSY, or wait, as if the queue is full. Reads still drain the queue while blocked.
+void set_writes_blocked(Queue *queue, int writes_blocked)
+{
+    mutex_lock(&queue->lock);
+    queue->writes_blocked = writes_blocked;
+    mutex_unlock(&queue->lock);
+
+    // Writers waiting while blocked may now have room
+    if (!writes_blocked)
+        wake_up_interruptible_all(&queue->write_wait);
+}
+
+// Sets what writes to a full queue fail with. Returns -EINVAL unless it's `EBUSY` or `ENOSPC`.
+int set_full_errno(Queue *queue, unsigned int full_errno)
+{
+    if (full_errno != EBUSY && full_errno != ENOSPC)
+        return -EINVAL;
+
+    mutex_lock(&queue->lock);
+    queue->full_errno = full_errno;
+    mutex_unlock(&queue->lock);
+
+    return 0;
+}
+
+// Sets how many messages must be queued after an enqueue for `SIGIO` to be sent, 0 or 1 sends it for every message.
+void set_notify_watermark(Queue *queue, unsigned int watermark)
+{
+    mutex_lock(&queue->lock);
+    queue->notify_watermark = watermark;
+    mutex_unlock(&queue->lock);
+}
+
+// Sets the byte `CHARDEV_IOC_READ_CONCAT` puts between messages.
+void set_delimiter(Queue *queue, char delimiter)
+{
+    mutex_lock(&queue->lock);
+    queue->delimiter = delimiter;
+    mutex_unlock(&queue->lock);
+}
+
+// Sets whether a message identical to the newest one is dropped instead of enqueued.
+void set_dedup(Queue *queue, int dedup)
+{
+    mutex_lock(&queue->lock);
+    queue->dedup = dedup;
+    mutex_unlock(&queue->lock);
+}
+
+// Sets whether enqueued messages record the time.
+void set_timestamp(Queue *queue, int timestamp)
+{
+    mutex_lock(&queue->lock);
+    queue->timestamp = timestamp;
+    mutex_unlock(&queue->lock);
+}
+
+// Sets whether every file open for reading reads every message. When enabled each starts from the oldest message.
+void set_broadcast(Queue *queue, int broadcast)
+{
+    Handle *handle;
+    u64 oldest_id;
+
+    mutex_lock(&queue->lock);
+
+    if (broadcast && !queue->broadcast)
+    {
+        oldest_id = list_empty(&queue->messages) ? queue->next_id
+                                                 : list_first_entry(&queue->messages, Message, list)->id;
+        list_for_each_entry(handle, &queue->handles, list)
+        {
+            if (handle->reader)
+                handle->cursor = oldest_id;
+        }
+    }
+    queue->broadcast = broadcast;
+
+    mutex_unlock(&queue->lock);
+}
+
+// In broadcast mode, makes `handle` read again from the oldest message still queued. Messages every open file had read
+// are already gone, so can't be read again. Returns -EINVAL unless in broadcast mode.
+int rewind_handle(Queue *queue, Handle *handle)
+{
+    mutex_lock(&queue->lock);
+
+    if (!queue->broadcast)
+    {
+        mutex_unlock(&queue->lock);
+        return -EINVAL;
+    }
+    handle->cursor = list_empty(&queue->messages) ? queue->next_id
+                                                  : list_first_entry(&queue->messages, Message, list)->id;
+
+    mutex_unlock(&queue->lock);
+
+    // Polling readers of this file may now have a message
+    wake_up_interruptible_all(&queue->read_wait);
+    return 0;
+}
+
+// Whether there may be room for `count` messages of `bytes` in total. This is a wait condition so doesn't take the lock.
+static int has_room(Queue *queue, int count, u64 bytes)
+{
+    u64 byte_budget = READ_ONCE(queue->byte_budget);
+
+    return !READ_ONCE(queue->writes_blocked) && READ_ONCE(queue->size) + count <= READ_ONCE(queue->capacity) &&
+           (byte_budget == 0 || READ_ONCE(queue->bytes) + bytes <= byte_budget);
+}
+
+// Whether `count` messages of `bytes` in total would fit in the empty queue, so are worth waiting for room for.
+static int could_fit(Queue *queue, int count, u64 bytes)
+{
+    u64 byte_budget = READ_ONCE(queue->byte_budget);
+
+    return count <= READ_ONCE(queue->capacity) && (byte_budget == 0 || bytes <= byte_budget);
+}
+
+// Whether there may be a message for `handle` to read. This is a wait condition so doesn't take the lock.
+static int has_message(Handle *handle)
+{
+    Queue *queue = handle->queue;
+
+    if (READ_ONCE(queue->frozen))
+        return 0;
+    if (READ_ONCE(queue->broadcast))
+        return READ_ONCE(queue->next_id) > READ_ONCE(handle->cursor);
+    return READ_ONCE(queue->size) > 0;
+}
+
+// Creates the state of a newly opened file. In broadcast mode it reads messages enqueued from now on.
+Handle *open_handle(Queue *queue, int reader, int writer)
+{
+    Handle *handle = kmalloc(sizeof(Handle), GFP_KERNEL);
+    if (handle == NULL)
+        return NULL;
+    handle->queue = queue;
+    handle->reader = reader;
+    handle->writer = writer;
+    handle->stream = 0;
+    handle->rate = 0;
+    handle->rate_next_ns = 0;
+    atomic64_set(&handle->bytes_written, 0);
+    atomic64_set(&handle->bytes_read, 0);
+    atomic64_set(&handle->messages_written, 0);
+    atomic64_set(&handle->messages_read, 0);
+
+    mutex_lock(&queue->lock);
+    handle->cursor = queue->next_id;
+    list_add_tail(&handle->list, &queue->handles);
+    queue->open_count++;
+    if (writer)
+        queue->writers++;
+    mutex_unlock(&queue->lock);
+
+    return handle;
+}
+
+// Counts `count` messages of `bytes` in total as read through this open file, and by the queue.
+static void count_read(Handle *handle, int count, u64 bytes)
+{
+    atomic64_add(count, &handle->messages_read);
+    atomic64_add(bytes, &handle->bytes_read);
+    atomic64_add(count, &handle->queue->stats.messages_read);
+}
+
+// Counts `count` messages of `bytes` in total as written through this open file, and by the queue.
+static void count_written(Handle *handle, int count, u64 bytes)
+{
+    atomic64_add(count, &handle->messages_written);
+    atomic64_add(bytes, &handle->bytes_written);
+    atomic64_add(count, &handle->queue->stats.messages_written);
+}
+
+// Copies the counters of this open file into a user space struct.
+static long get_my_stats(Handle *handle, struct chardev_my_stats __user *arg)
+{
+    struct chardev_my_stats stats = {
+        .bytes_written = atomic64_read(&handle->bytes_written),
+        .bytes_read = atomic64_read(&handle->bytes_read),
+        .messages_written = atomic64_read(&handle->messages_written),
+        .messages_read = atomic64_read(&handle->messages_read),
+    };
+
+    if (copy_to_user(arg, &stats, sizeof(stats)))
+        return -EFAULT;
+    return SUCCESS;
+}
+
+// Sets whether reads from this open file can consume part of a message.
+void set_stream(Handle *handle, int stream)
+{
+    Queue *queue = handle->queue;
+
+    mutex_lock(&queue->lock);
+    handle->stream = stream;
+    mutex_unlock(&queue->lock);
+}
+
+// Sets the maximum number of messages written per second by this open file, 0 for no limit.
+void set_rate(Handle *handle, unsigned int rate)
+{
+    Queue *queue = handle->queue;
+
+    mutex_lock(&queue->lock);
+    handle->rate = rate;
+    handle->rate_next_ns = 0;
+    mutex_unlock(&queue->lock);
+}
+
+// Takes a token for each of `count` messages from the open file's rate limit.
+// The bucket holds a single token, but may go into debt so larger writes can go ahead and delay later ones.
+// Returns 0 if they were taken, otherwise how many nanoseconds until the next token.
+static u64 take_rate_tokens(Handle *handle, int count)
+{
+    Queue *queue = handle->queue;
+    u64 now;
+    u64 wait = 0;
+
+    mutex_lock(&queue->lock);
+    if (handle->rate != 0)
+    {
+        now = ktime_get_ns();
+        if (now < handle->rate_next_ns)
+            wait = handle->rate_next_ns - now;
+        else
+            handle->rate_next_ns = now + div_u64(NSEC_PER_SEC * count, handle->rate);
+    }
+    mutex_unlock(&queue->lock);
+
+    return wait;
+}
+
+// Gives back the tokens taken for `count` messages which weren't enqueued, so failed writes don't use up the rate limit.
+static void return_rate_tokens(Handle *handle, int count)
+{
+    Queue *queue = handle->queue;
+    u64 cost;
+
+    mutex_lock(&queue->lock);
+    // Changing the rate refills the bucket, in which case there's nothing to give back
+    if (handle->rate != 0)
+    {
+        cost = div_u64(NSEC_PER_SEC * count, handle->rate);
+        handle->rate_next_ns = handle->rate_next_ns > cost ? handle->rate_next_ns - cost : 0;
+    }
+    mutex_unlock(&queue->lock);
+}
+
+// Waits for the open file's rate limit to allow another `count` messages.
+// Returns -EAGAIN instead if the file has `O_NONBLOCK`, or -ERESTARTSYS if interrupted.
+static int wait_for_rate(struct file *filp, int count)
+{
+    Handle *handle = filp->private_data;
+    u64 wait;
+
+    while ((wait = take_rate_tokens(handle, count)) != 0)
+    {
+        if (filp->f_flags & O_NONBLOCK)
+            return -EAGAIN;
+        if (msleep_interruptible(DIV_ROUND_UP_ULL(wait, NSEC_PER_MSEC)))
+            return -ERESTARTSYS;
+    }
+
+    return 0;
+}
+
+// Frees the state of a closed file, along with any broadcast messages only it hadn't read.
+void close_handle(Handle *handle)
+{
+    Queue *queue = handle->queue;
+    LIST_HEAD(reclaimed);
+    int count = 0;
+    int hangup = 0;
+    int drained = 0;
+
+    mutex_lock(&queue->lock);
+    list_del(&handle->list);
+    queue->open_count--;
+    if (handle->writer)
+        hangup = --queue->writers == 0;
+    if (queue->broadcast)
+        count = reclaim_read_messages(queue, &reclaimed);
+    // With `CHARDEV_IOC_SET_DRAIN_ON_CLOSE` the last close flushes the queue, like `CHARDEV_IOC_FLUSH`
+    if (queue->open_count == 0 && queue->drain_on_close)
+    {
+        list_splice_init(&queue->messages, &reclaimed);
+        queue->size = 0;
+        queue->bytes = 0;
+        queue->generation++;
+        drained = 1;
+    }
+    mutex_unlock(&queue->lock);
+
+    free_messages(&reclaimed);
+    if (count > 0 || drained)
+    {
+        publish_stats(queue);
+        wake_up_interruptible_all(&queue->write_wait);
+    }
+    // Polling readers may now see a hangup
+    if (hangup)
+        wake_up_interruptible(&queue->read_wait);
+    kfree(handle);
+}
+
+// Sets the order messages are read in. Returns -EINVAL if `mode` isn't a `CHARDEV_MODE_*`.
+int set_mode(Queue *queue, int mode)
+{
+    if (mode != CHARDEV_MODE_FIFO && mode != CHARDEV_MODE_LIFO && mode != CHARDEV_MODE_PRIORITY)
+        return -EINVAL;
+
+    // Taking the lock means an in-flight read sees either the old or new mode, never a mix
+    mutex_lock(&queue->lock);
+    queue->mode = mode;
+    mutex_unlock(&queue->lock);
+
+    return 0;
+}
+
+Queue **queues = NULL; // Indexed by minor number
+
+// Locks devices `source` and `target`, which must be different.
+static void lock_both(unsigned int source, unsigned int target)
+{
+    // Always taking the lock of the lower minor number first avoids deadlocking with a move the other way
+    if (source < target)
+    {
+        mutex_lock(&queues[source]->lock);
+        mutex_lock_nested(&queues[target]->lock, SINGLE_DEPTH_NESTING);
+    }
+    else
+    {
+        mutex_lock(&queues[target]->lock);
+        mutex_lock_nested(&queues[source]->lock, SINGLE_DEPTH_NESTING);
+    }
+}
+
+// Moves every message from device `source` to the end of device `target`, keeping their order.
+// Returns the number of messages moved, -EINVAL if the devices are the same or -EBUSY if they don't fit.
+int move_messages(unsigned int source, unsigned int target)
+{
+    Queue *from = queues[source];
+    Queue *to = queues[target];
+    Message *message;
+    int count, notify;
+
+    if (source == target)
+        return -EINVAL;
+
+    lock_both(source, target);
+
+    count = from->size;
+    if (to->writes_blocked || to->size + count > to->capacity ||
+        (to->byte_budget != 0 && to->bytes + from->bytes > to->byte_budget))
+    {
+        mutex_unlock(&from->lock);
+        mutex_unlock(&to->lock);
+        return -EBUSY;
+    }
+    // Renumber so ids and sequence numbers still increase through the target's list
+    list_for_each_entry(message, &from->messages, list)
+    {
+        message->id = to->next_id++;
+        message->sequence = to->next_sequence++;
+    }
+    list_splice_tail_init(&from->messages, &to->messages);
+    from->size = 0;
+    to->size += count;
+    to->bytes += from->bytes;
+    from->bytes = 0;
+    if (to->size > atomic64_read(&to->stats.high_water))
+        atomic64_set(&to->stats.high_water, to->size);
+    notify = to->size >= to->notify_watermark;
+
+    mutex_unlock(&from->lock);
+    mutex_unlock(&to->lock);
+
+    if (count > 0)
+    {
+        publish_stats(from);
+        publish_stats(to);
+        wake_up_interruptible_all(&from->write_wait);
+        wake_up_interruptible(&to->read_wait);
+        if (notify)
+            kill_fasync(&to->async, SIGIO, POLL_IN);
+    }
+
+    return count;
+}
+
+// Moves the message which would be read next, in the mode of device `source`, to the end of device `target`.
+// Returns 0, -EINVAL if the devices are the same, -EAGAIN if `source` is empty or frozen or -EBUSY if the message
+// doesn't fit, in which case neither device changes.
+int handoff_message(unsigned int source, unsigned int target)
+{
+    Queue *from = queues[source];
+    Queue *to = queues[target];
+    Message *message;
+    int notify;
+
+    if (source == target)
+        return -EINVAL;
+
+    lock_both(source, target);
+
+    if (from->size == 0 || from->frozen)
+    {
+        mutex_unlock(&from->lock);
+        mutex_unlock(&to->lock);
+        return -EAGAIN;
+    }
+    message = next_message(from);
+    if (!has_room(to, 1, message->length))
+    {
+        mutex_unlock(&from->lock);
+        mutex_unlock(&to->lock);
+        return -EBUSY;
+    }
+    message->id = to->next_id++;
+    message->sequence = to->next_sequence++;
+    list_move_tail(&message->list, &to->messages);
+    from->size--;
+    from->bytes -= message->length;
+    to->size++;
+    to->bytes += message->length;
+    if (to->size > atomic64_read(&to->stats.high_water))
+        atomic64_set(&to->stats.high_water, to->size);
+    notify = to->size >= to->notify_watermark;
+
+    mutex_unlock(&from->lock);
+    mutex_unlock(&to->lock);
+
+    publish_stats(from);
+    publish_stats(to);
+    wake_up_interruptible(&from->write_wait);
+    wake_up_interruptible(&to->read_wait);
+    if (notify)
+        kill_fasync(&to->async, SIGIO, POLL_IN);
+
+    return 0;
+}
+
+// Mirrors every message enqueued on device `source` to device `target` from now on, or stops if `target` is
+// `CHARDEV_TEE_OFF`. Returns -EINVAL if the devices are the same.
+int set_tee(unsigned int source, unsigned int target)
+{
+    Queue *queue = queues[source];
+
+    if (source == target)
+        return -EINVAL;
+
+    mutex_lock(&queue->lock);
+    queue->tee = target == CHARDEV_TEE_OFF ? NULL : queues[target];
+    mutex_unlock(&queue->lock);
+
+    return 0;
+}
+
+// Frees every queue and the messages in them.
+void destroy_queues(void)
+{
+    int i;
+
+    for (i = 0; i < num_devices; i++)
+    {
+        if (queues[i] == NULL)
+            continue;
+        flush_queue(queues[i]);
+        free_page((unsigned long)queues[i]->stats_page);
+        kfree(queues[i]);
+    }
+    kfree(queues);
+    queues = NULL;
+}
+
+struct class *chardev_class = NULL; // Shown in sysfs as `/sys/class/chardev`
+
+// Shows the number of messages in the queue, e.g. `cat /sys/class/chardev/chardev0/queue_len`.
+static ssize_t queue_len_show(struct device *dev, struct device_attribute *attr, char *buf)
+{
+    Queue *queue = dev_get_drvdata(dev);
+
+    return sysfs_emit(buf, "%d\n", queue_length(queue));
+}
+static DEVICE_ATTR_RO(queue_len);
+
+// Flushes the queue when a non-zero number is written, e.g. `echo 1 > /sys/class/chardev/chardev0/flush`.
+static ssize_t flush_store(struct device *dev, struct device_attribute *attr, const char *buf, size_t count)
+{
+    Queue *queue = dev_get_drvdata(dev);
+    int value;
+    int result;
+
+    result = kstrtoint(buf, 0, &value);
+    if (result < 0)
+        return result;
+    if (value != 0)
+        flush_queue(queue);
+
+    return count;
+}
+static DEVICE_ATTR_WO(flush);
+
+static struct attribute *chardev_attrs[] = {
+    &dev_attr_queue_len.attr,
+    &dev_attr_flush.attr,
+    NULL,
+};
+ATTRIBUTE_GROUPS(chardev);
+
+// Creates the sysfs class and a device in it for each queue, named `chardevN`.
+int create_devices(void)
+{
+    struct device *device;
+    int i;
+
+#if LINUX_VERSION_CODE >= KERNEL_VERSION(6, 4, 0)
+    chardev_class = class_create(DEVICE_NAME);
+#else
+    chardev_class = class_create(THIS_MODULE, DEVICE_NAME);
+#endif
+    if (IS_ERR(chardev_class))
+        return PTR_ERR(chardev_class);
+
+    for (i = 0; i < num_devices; i++)
+    {
+        device = device_create_with_groups(
+            chardev_class, NULL, MKDEV(Major, i), queues[i], chardev_groups, DEVICE_NAME "%d", i);
+        if (IS_ERR(device))
+        {
+            while (i--)
+                device_destroy(chardev_class, MKDEV(Major, i));
+            class_destroy(chardev_class);
+            return PTR_ERR(device);
+        }
+    }
+
+    return 0;
+}
+
+// Removes the devices and class created by `create_devices`.
+void destroy_devices(void)
+{
+    int i;
+
+    for (i = 0; i < num_devices; i++)
+        device_destroy(chardev_class, MKDEV(Major, i));
+    class_destroy(chardev_class);
+    chardev_class = NULL;
+}
+
+struct proc_dir_entry *proc_dir = NULL; // `/proc/chardev`
+
+// Prints the statistics of every device as `chardevN.key value` lines, e.g. `cat /proc/chardev/stats`.
+static int stats_show(struct seq_file *m, void *v)
+{
+    Stats *stats;
+    int i;
+
+    for (i = 0; i < num_devices; i++)
+    {
+        stats = &queues[i]->stats;
+        seq_printf(m, DEVICE_NAME "%d.messages_written %lld\n", i, atomic64_read(&stats->messages_written));
+        seq_printf(m, DEVICE_NAME "%d.messages_read %lld\n", i, atomic64_read(&stats->messages_read));
+        seq_printf(m, DEVICE_NAME "%d.rejected_too_long %lld\n", i, atomic64_read(&stats->rejected_too_long));
+        seq_printf(m, DEVICE_NAME "%d.rejected_busy %lld\n", i, atomic64_read(&stats->rejected_busy));
+        seq_printf(m, DEVICE_NAME "%d.high_water %lld\n", i, atomic64_read(&stats->high_water));
+    }
+
+    return 0;
+}
+
+static int stats_open(struct inode *inode, struct file *file)
+{
+    return single_open(file, stats_show, NULL);
+}
+
+static const struct proc_ops stats_ops = {
+    .proc_open = stats_open,
+    .proc_read = seq_read,
+    .proc_lseek = seq_lseek,
+    .proc_release = single_release,
+};
+
+// Creates `/proc/chardev` and the files in it.
+int create_proc_entries(void)
+{
+    proc_dir = proc_mkdir(DEVICE_NAME, NULL);
+    if (proc_dir == NULL)
+        return -ENOMEM;
+    if (proc_create("stats", S_IRUGO, proc_dir, &stats_ops) == NULL)
+    {
+        proc_remove(proc_dir);
+        return -ENOMEM;
+    }
+
+    return 0;
+}
+
+// Removes `/proc/chardev` and the files in it.
+void destroy_proc_entries(void)
+{
+    proc_remove(proc_dir);
+    proc_dir = NULL;
+}
+
+struct dentry *debugfs_dir = NULL; // `/sys/kernel/debug/chardev`
+
+// Prints every queued message of every device as `chardevN length hex` lines, oldest first, without removing them,
+// e.g. `cat /sys/kernel/debug/chardev/dump`.
+static int dump_show(struct seq_file *m, void *v)
+{
+    Message *message;
+    int i;
+    int offset;
+
+    for (i = 0; i < num_devices; i++)
+    {
+        mutex_lock(&queues[i]->lock);
+        list_for_each_entry(message, &queues[i]->messages, list)
+        {
+            seq_printf(m, DEVICE_NAME "%d %d ", i, message->length);
+            // `%*ph` prints at most 64 bytes at a time
+            for (offset = 0; offset < message->length; offset += 64)
+                seq_printf(m, "%*phN", min(message->length - offset, 64), message->string + offset);
+            seq_putc(m, '\n');
+        }
+        mutex_unlock(&queues[i]->lock);
+    }
+
+    return 0;
+}
+
+static int dump_open(struct inode *inode, struct file *file)
+{
+    return single_open(file, dump_show, NULL);
+}
+
+static const struct file_operations dump_ops = {
+    .owner = THIS_MODULE,
+    .open = dump_open,
+    .read = seq_read,
+    .llseek = seq_lseek,
+    .release = single_release,
+};
+
+// Creates `/sys/kernel/debug/chardev` and the files in it. Failing to is not an error, as they're only for debugging.
+void create_debugfs_entries(void)
+{
+    debugfs_dir = debugfs_create_dir(DEVICE_NAME, NULL);
+    debugfs_create_file("dump", S_IRUSR, debugfs_dir, NULL, &dump_ops);
+}
+
+// Removes `/sys/kernel/debug/chardev` and the files in it.
+void destroy_debugfs_entries(void)
+{
+    debugfs_remove_recursive(debugfs_dir);
+    debugfs_dir = NULL;
+}
+
+// Copies the message which would be read next into a user space buffer, leaving it in the queue.
+static long device_peek(Queue *queue, struct chardev_buffer __user *arg)
+{
+    struct chardev_buffer target;
+    char *item;
+    int item_length;
+
+    if (copy_from_user(&target, arg, sizeof(target)))
+        return -EFAULT;
+
+    item = kvmalloc(max_string_length, GFP_KERNEL);
+    if (item == NULL)
+        return -ENOMEM;
+    item_length = peek(queue, item, target.length);
+    if (item_length < 0)
+    {
+        kvfree(item);
+        return item_length;
+    }
+
+    if (copy_to_user(u64_to_user_ptr(target.data), item, item_length))
+    {
+        kvfree(item);
+        return -EFAULT;
+    }
+    kvfree(item);
+
+    return item_length;
+}
+
+// Copies the oldest message into a user space buffer and moves it to the end of the queue.
+static long device_rotate(Queue *queue, struct chardev_buffer __user *arg)
+{
+    struct chardev_buffer target;
+    char *item;
+    int item_length;
+
+    if (copy_from_user(&target, arg, sizeof(target)))
+        return -EFAULT;
+
+    item = kvmalloc(max_string_length, GFP_KERNEL);
+    if (item == NULL)
+        return -ENOMEM;
+    item_length = rotate(queue, item, target.length);
+    if (item_length < 0)
+    {
+        kvfree(item);
+        return item_length;
+    }
+
+    // The message has already moved, like a read which fails to copy it has already removed it
+    if (copy_to_user(u64_to_user_ptr(target.data), item, item_length))
+    {
+        kvfree(item);
+        return -EFAULT;
+    }
+    kvfree(item);
+
+    return item_length;
+}
+
+// Copies the message at a position into a user space buffer without removing it.
+static long device_peek_at(Queue *queue, struct chardev_index_buffer __user *arg)
+{
+    struct chardev_index_buffer target;
+    char *item;
+    int item_length;
+
+    if (copy_from_user(&target, arg, sizeof(target)))
+        return -EFAULT;
+
+    item = kvmalloc(max_string_length, GFP_KERNEL);
+    if (item == NULL)
+        return -ENOMEM;
+    item_length = peek_at(queue, target.index, item, target.length);
+    if (item_length < 0)
+    {
+        kvfree(item);
+        return item_length;
+    }
+
+    if (copy_to_user(u64_to_user_ptr(target.data), item, item_length))
+    {
+        kvfree(item);
+        return -EFAULT;
+    }
+    kvfree(item);
+
+    return item_length;
+}
+
+// Sets the label of the device from a user space buffer, which can be at most `CHARDEV_LABEL_MAX` bytes.
+static long device_set_label(Queue *queue, struct chardev_buffer __user *arg)
+{
+    struct chardev_buffer source;
+    char label[CHARDEV_LABEL_MAX];
+
+    if (copy_from_user(&source, arg, sizeof(source)))
+        return -EFAULT;
+    if (source.length > CHARDEV_LABEL_MAX)
+        return -EINVAL;
+    if (copy_from_user(label, u64_to_user_ptr(source.data), source.length))
+        return -EFAULT;
+
+    set_label(queue, label, source.length);
+    return SUCCESS;
+}
+
+// Sets the prefix of the device from a user space buffer, which can be at most `CHARDEV_PREFIX_MAX` bytes.
+static long device_set_prefix(Queue *queue, struct chardev_buffer __user *arg)
+{
+    struct chardev_buffer source;
+    char prefix[CHARDEV_PREFIX_MAX];
+
+    if (copy_from_user(&source, arg, sizeof(source)))
+        return -EFAULT;
+    if (source.length > CHARDEV_PREFIX_MAX)
+        return -EINVAL;
+    if (copy_from_user(prefix, u64_to_user_ptr(source.data), source.length))
+        return -EFAULT;
+
+    set_prefix(queue, prefix, source.length);
+    return SUCCESS;
+}
+
+// Appends a user space buffer of at most `CHARDEV_SUFFIX_MAX` bytes to every queued message.
+static long device_append_all(Queue *queue, struct chardev_buffer __user *arg)
+{
+    struct chardev_buffer source;
+    char suffix[CHARDEV_SUFFIX_MAX];
+
+    if (copy_from_user(&source, arg, sizeof(source)))
+        return -EFAULT;
+    if (source.length > CHARDEV_SUFFIX_MAX)
+        return -EINVAL;
+    if (copy_from_user(suffix, u64_to_user_ptr(source.data), source.length))
+        return -EFAULT;
+
+    return append_all(queue, suffix, source.length);
+}
+
+// Copies the label of the device into a user space buffer, returning its length.
+static long device_get_label(Queue *queue, struct chardev_buffer __user *arg)
+{
+    struct chardev_buffer target;
+    char label[CHARDEV_LABEL_MAX];
+    int length;
+
+    if (copy_from_user(&target, arg, sizeof(target)))
+        return -EFAULT;
 
-// Your device driver should perform the following operations:
-// - When the module is loaded, the device is created. An empty list of messages is created as well.
-// - Removing the module deallocates all messages, removes the list of messages and removes the device.
-// - Reading from the device returns one message, and removes this message from the kernel list.
-//   If the list of messages is empty, the reader returns -EAGAIN.
-// - Writing to the device stores the message in kernel space and adds it to the list if the message
-//   is below the maximum size, and the limit of the number of all messages stored in the kernel
-//   wouldn't be surpassed with this message. If the message is too big, -EINVAL is returned,
-//   and if the limit of the number of all messages was surpassed, -EBUSY is returned.
-// - The kernel module which implements this driver must be called charDeviceDriver.ko.
+    length = get_label(queue, label);
+    if (length > target.length)
+        return -EMSGSIZE;
+    if (copy_to_user(u64_to_user_ptr(target.data), label, length))
+        return -EFAULT;
 
-// You need to ensure that your code deals with multiple attempts at reading and writing at the same time.
-// Moreover, your code should handle several read and write attempts concurrently.
-// Your critical sections should be as short as possible.
-// The reader should obtain the messages in a FIFO (first in first out) manner.
+    return length;
+}
+
+// Returns how long `read` waits for a message, in jiffies.
+static long default_read_timeout(struct file *file)
+{
+    return (file->f_flags & O_NONBLOCK) ? 0 : MAX_SCHEDULE_TIMEOUT;
+}
+
+// Reads a message into a user space buffer, waiting at most the given number of milliseconds for one.
+static long device_read_timeout(struct file *file, struct chardev_timeout_buffer __user *arg)
+{
+    struct chardev_timeout_buffer target;
 
-#define MAX_STRING_LENGTH 4096
-#define MAX_QUEUE_SIZE 1000
+    if (copy_from_user(&target, arg, sizeof(target)))
+        return -EFAULT;
 
-DEFINE_MUTEX(mutex);
+    return read_message(
+        file, u64_to_user_ptr(target.data), target.length, msecs_to_jiffies(target.timeout_ms), NULL, NULL);
+}
 
-typedef struct Queue
+// Reads a message into a user space buffer, along with the time it was enqueued.
+static long device_read_ts(struct file *file, struct chardev_ts_buffer __user *arg)
 {
-    char strings[MAX_QUEUE_SIZE][MAX_STRING_LENGTH];
-    int sizes[MAX_QUEUE_SIZE];
-    int front;
-    int end;
-    int size;
-} Queue;
+    struct chardev_ts_buffer target;
+    ssize_t length;
 
-// Create a queue.
-Queue *create_queue(void)
+    if (copy_from_user(&target, arg, sizeof(target)))
+        return -EFAULT;
+
+    length = read_message(
+        file, u64_to_user_ptr(target.data), target.length, default_read_timeout(file), &target.timestamp, NULL);
+    if (length < 0)
+        return length;
+
+    // The message has already been consumed, so there's no way to return it if this fails
+    if (put_user(target.timestamp, &arg->timestamp))
+        return -EFAULT;
+
+    return length;
+}
+
+// Reads a message into a user space buffer, along with its sequence number.
+static long device_read_seq(struct file *file, struct chardev_seq_buffer __user *arg)
 {
-    Queue *q = kmalloc(sizeof(Queue), GFP_KERNEL);
-    if (q == NULL)
+    struct chardev_seq_buffer target;
+    ssize_t length;
+
+    if (copy_from_user(&target, arg, sizeof(target)))
+        return -EFAULT;
+
+    length = read_message(
+        file, u64_to_user_ptr(target.data), target.length, default_read_timeout(file), NULL, &target.sequence);
+    if (length < 0)
+        return length;
+
+    // As with `device_read_ts`, the message can't be returned to the queue if this fails
+    if (put_user(target.sequence, &arg->sequence))
+        return -EFAULT;
+
+    return length;
+}
+
+// Reads a message into a user space buffer after its length, returning the number of bytes used.
+static long device_read_framed(struct file *file, struct chardev_buffer __user *arg)
+{
+    struct chardev_buffer target;
+    char __user *data;
+    __le32 header;
+    ssize_t length;
+
+    if (copy_from_user(&target, arg, sizeof(target)))
+        return -EFAULT;
+    if (target.length < sizeof(header))
+        return -EMSGSIZE;
+
+    data = u64_to_user_ptr(target.data);
+    length = read_message(
+        file, data + sizeof(header), target.length - sizeof(header), default_read_timeout(file), NULL, NULL);
+    if (length < 0)
+        return length;
+
+    // As with `device_read_ts`, the message can't be returned to the queue if this fails
+    header = cpu_to_le32(length);
+    if (copy_to_user(data, &header, sizeof(header)))
+        return -EFAULT;
+
+    return sizeof(header) + length;
+}
+
+// Removes every message into a user space buffer as length prefixed records, returning the number of bytes used.
+static long device_drain(Handle *handle, struct chardev_buffer __user *arg)
+{
+    Queue *queue = handle->queue;
+    struct chardev_buffer target;
+    Message *message, *next;
+    LIST_HEAD(messages);
+    char __user *data;
+    __u32 length;
+    int count;
+    u64 bytes = 0;
+    long result = 0;
+
+    if (!handle->reader)
+        return -EBADF;
+    if (copy_from_user(&target, arg, sizeof(target)))
+        return -EFAULT;
+
+    count = drain(queue, &messages, target.length, sizeof(length));
+    if (count < 0)
+        return count;
+    list_for_each_entry(message, &messages, list)
     {
-        printk(KERN_ALERT "Error: could not allocate memory for queue\n");
-        return NULL;
+        bytes += message->length;
     }
-    q->front = 0;
-    q->end = MAX_QUEUE_SIZE - 1;
-    q->size = 0;
-    return q;
+    count_read(handle, count, bytes);
+    publish_stats(queue);
+
+    // The messages have already been removed, so if a copy fails the rest are dropped
+    data = u64_to_user_ptr(target.data);
+    list_for_each_entry_safe(message, next, &messages, list)
+    {
+        length = message->length;
+        if (result >= 0)
+        {
+            if (copy_to_user(data, &length, sizeof(length)) ||
+                copy_to_user(data + sizeof(length), message->string, length))
+                result = -EFAULT;
+            else
+            {
+                data += sizeof(length) + length;
+                result += sizeof(length) + length;
+            }
+        }
+        kvfree(message);
+    }
+
+    return result;
 }
 
-// Add a string to the queue.
-int enqueue(Queue *queue, char *string, int length)
+// Replaces every message with the length prefixed records in a user space buffer, returning how many there were.
+// Returns -EINVAL if a record is cut short or its message is longer than `max_string_length`.
+static long device_load_template(Queue *queue, struct chardev_buffer __user *arg)
 {
-    mutex_lock(&mutex);
+    struct chardev_buffer source;
+    Message *message;
+    LIST_HEAD(messages);
+    char __user *data;
+    u64 offset = 0;
+    u64 bytes = 0;
+    __u32 length;
+    int count = 0;
+    long result;
+
+    if (copy_from_user(&source, arg, sizeof(source)))
+        return -EFAULT;
 
-    if (queue->size == MAX_QUEUE_SIZE)
+    data = u64_to_user_ptr(source.data);
+    while (offset < source.length)
     {
-        // printk(KERN_INFO "[Queue] Queue is full\n");
-        mutex_unlock(&mutex);
-        return -1;
+        if (source.length - offset < sizeof(length))
+        {
+            free_messages(&messages);
+            return -EINVAL;
+        }
+        if (copy_from_user(&length, data + offset, sizeof(length)))
+        {
+            free_messages(&messages);
+            return -EFAULT;
+        }
+        offset += sizeof(length);
+        if (length > max_string_length || source.length - offset < length)
+        {
+            free_messages(&messages);
+            return -EINVAL;
+        }
+        // Checked as it goes so a huge buffer can't allocate more than the queue could hold
+        if (++count > queue_capacity(queue))
+        {
+            free_messages(&messages);
+            return -EBUSY;
+        }
+        message = create_message(length);
+        if (message == NULL)
+        {
+            free_messages(&messages);
+            return -ENOMEM;
+        }
+        list_add_tail(&message->list, &messages);
+        if (copy_from_user(message->string, data + offset, length))
+        {
+            free_messages(&messages);
+            return -EFAULT;
+        }
+        offset += length;
+        bytes += length;
     }
 
-    queue->end = (queue->end + 1) % MAX_QUEUE_SIZE;
-    memcpy(queue->strings[queue->end], string, length);
-    queue->sizes[queue->end] = length;
-    queue->size++;
+    result = replace_messages(queue, &messages, count, bytes);
+    if (result < 0)
+        free_messages(&messages);
+    return result;
+}
 
-    mutex_unlock(&mutex);
+// Copies every message into a user space buffer without removing them, returning the number of bytes used.
+static long device_snapshot(Queue *queue, struct chardev_buffer __user *arg)
+{
+    struct chardev_buffer target;
+    char *records;
+    long result;
 
-    return 0;
+    if (copy_from_user(&target, arg, sizeof(target)))
+        return -EFAULT;
+
+    result = snapshot(queue, &records, target.length);
+    if (result > 0 && copy_to_user(u64_to_user_ptr(target.data), records, result))
+        result = -EFAULT;
+    kvfree(records);
+
+    return result;
 }
 
-// Removes a string from the queue.
-int dequeue(Queue *queue, char *string)
+// Removes every message into a user space buffer, joined by the delimiter, returning the number of bytes used.
+static long device_read_concat(Handle *handle, struct chardev_buffer __user *arg)
 {
-    int length;
+    Queue *queue = handle->queue;
+    struct chardev_buffer target;
+    Message *message, *next;
+    LIST_HEAD(messages);
+    char __user *data;
+    char delimiter = READ_ONCE(queue->delimiter);
+    int count;
+    int first = 1;
+    u64 bytes = 0;
+    long result = 0;
 
-    mutex_lock(&mutex);
+    if (!handle->reader)
+        return -EBADF;
+    if (copy_from_user(&target, arg, sizeof(target)))
+        return -EFAULT;
 
-    if (queue->size == 0)
+    // `count` messages only need `count - 1` delimiters, so there's room for one more than the buffer, clamped so a
+    // huge length can't wrap around to 0
+    count = drain(queue, &messages, min_t(u64, target.length, SIZE_MAX - 1) + 1, sizeof(delimiter));
+    if (count < 0)
+        return count;
+    list_for_each_entry(message, &messages, list)
     {
-        // printk(KERN_INFO "[Queue] Queue is empty\n");
-        mutex_unlock(&mutex);
-        return -1;
+        bytes += message->length;
     }
+    count_read(handle, count, bytes);
+    publish_stats(queue);
 
-    length = queue->sizes[queue->front];
-    memcpy(string, queue->strings[queue->front], length);
-    queue->front = (queue->front + 1) % MAX_QUEUE_SIZE;
-    queue->size--;
+    // As with `device_drain`, if a copy fails the rest are dropped
+    data = u64_to_user_ptr(target.data);
+    list_for_each_entry_safe(message, next, &messages, list)
+    {
+        if (result >= 0)
+        {
+            if ((!first && copy_to_user(data++, &delimiter, sizeof(delimiter))) ||
+                copy_to_user(data, message->string, message->length))
+                result = -EFAULT;
+            else
+            {
+                data += message->length;
+                result += message->length + !first;
+            }
+        }
+        first = 0;
+        kvfree(message);
+    }
 
-    mutex_unlock(&mutex);
+    return result;
+}
 
-    return length;
+// Writes a message with the given priority.
+static long device_write_prio(struct file *file, struct chardev_prio_buffer __user *arg)
+{
+    struct chardev_prio_buffer source;
+
+    if (copy_from_user(&source, arg, sizeof(source)))
+        return -EFAULT;
+
+    return write_message(
+        file, u64_to_user_ptr(source.data), source.length, source.priority, CHARDEV_DEFAULT_TYPE, 0);
+}
+
+// Writes a message with the given type.
+static long device_write_typed(struct file *file, struct chardev_typed_buffer __user *arg)
+{
+    struct chardev_typed_buffer source;
+
+    if (copy_from_user(&source, arg, sizeof(source)))
+        return -EFAULT;
+
+    return write_message(
+        file, u64_to_user_ptr(source.data), source.length, CHARDEV_DEFAULT_PRIORITY, source.type, 0);
+}
+
+// Copies a message removed from the queue into a user space buffer and frees it, returning its length.
+static long copy_removed(Handle *handle, Message *message, char __user *data)
+{
+    long result = message->length;
+
+    if (copy_to_user(data, message->string, message->length))
+        result = -EFAULT;
+    else
+        count_read(handle, 1, result);
+    kvfree(message);
+    // The message is gone either way
+    publish_stats(handle->queue);
+
+    return result;
+}
+
+// Reads the oldest message of the given type into a user space buffer, leaving messages of other types queued.
+// Unlike `read` this never waits, returning -EAGAIN if there's no message of the type.
+static long device_read_typed(struct file *file, struct chardev_typed_buffer __user *arg)
+{
+    Handle *handle = file->private_data;
+    struct chardev_typed_buffer target;
+    Message *message;
+
+    if (!handle->reader)
+        return -EBADF;
+    if (copy_from_user(&target, arg, sizeof(target)))
+        return -EFAULT;
+
+    message = dequeue_typed(handle->queue, target.type, target.length);
+    if (IS_ERR(message))
+        return PTR_ERR(message);
+    return copy_removed(handle, message, u64_to_user_ptr(target.data));
+}
+
+// Reads the newest message into a user space buffer, whatever the mode. Like `CHARDEV_IOC_READ_TYPED` this never
+// waits.
+static long device_read_reverse(struct file *file, struct chardev_buffer __user *arg)
+{
+    Handle *handle = file->private_data;
+    struct chardev_buffer target;
+    Message *message;
+
+    if (!handle->reader)
+        return -EBADF;
+    if (copy_from_user(&target, arg, sizeof(target)))
+        return -EFAULT;
+
+    message = dequeue_newest(handle->queue, target.length);
+    if (IS_ERR(message))
+        return PTR_ERR(message);
+    return copy_removed(handle, message, u64_to_user_ptr(target.data));
+}
+
+// Removes the message which would be read next if it matches the bytes in a user space buffer, otherwise returning
+// -EAGAIN. Like the read ioctls this never waits, and the message counts as read.
+static long device_pop_if_eq(struct file *file, struct chardev_buffer __user *arg)
+{
+    Handle *handle = file->private_data;
+    struct chardev_buffer source;
+    Message *message;
+    char *expected;
+
+    if (!handle->reader)
+        return -EBADF;
+    if (copy_from_user(&source, arg, sizeof(source)))
+        return -EFAULT;
+    // No message is longer than this, so it couldn't match
+    if (source.length > max_string_length)
+        return -EAGAIN;
+
+    expected = kvmalloc(source.length, GFP_KERNEL);
+    if (expected == NULL)
+        return -ENOMEM;
+    if (copy_from_user(expected, u64_to_user_ptr(source.data), source.length))
+    {
+        kvfree(expected);
+        return -EFAULT;
+    }
+    message = dequeue_if_equal(handle->queue, expected, source.length);
+    kvfree(expected);
+    if (IS_ERR(message))
+        return PTR_ERR(message);
+
+    count_read(handle, 1, message->length);
+    kvfree(message);
+    publish_stats(handle->queue);
+
+    return SUCCESS;
+}
+
+// Writes a message only if the queue is empty, otherwise returning -EEXIST.
+static long device_write_if_empty(struct file *file, struct chardev_buffer __user *arg)
+{
+    struct chardev_buffer source;
+
+    if (copy_from_user(&source, arg, sizeof(source)))
+        return -EFAULT;
+
+    return write_message(
+        file, u64_to_user_ptr(source.data), source.length, CHARDEV_DEFAULT_PRIORITY, CHARDEV_DEFAULT_TYPE,
+        ENQUEUE_IF_EMPTY);
 }
 
-Queue *queue = NULL;
+// Writes a message before the oldest, so in FIFO mode it's read next.
+static long device_write_front(struct file *file, struct chardev_buffer __user *arg)
+{
+    struct chardev_buffer source;
+
+    if (copy_from_user(&source, arg, sizeof(source)))
+        return -EFAULT;
+
+    return write_message(
+        file, u64_to_user_ptr(source.data), source.length, CHARDEV_DEFAULT_PRIORITY, CHARDEV_DEFAULT_TYPE,
+        ENQUEUE_FRONT);
+}
 
 // This function is called whenever a process tries to do an ioctl on our device file.
 // We get two extra parameters (additional to the inode and file structures, which all device functions get):
@@ -116,23 +2657,322 @@ static long device_ioctl(
     unsigned int ioctl_num,
     unsigned long ioctl_param)
 {
-    printk(KERN_INFO "Sorry, this operation isn't supported\n");
-    return -EINVAL;
+    Handle *handle = file->private_data;
+    Queue *queue = handle->queue;
+    __u32 value;
+    __u64 bytes;
+    int length;
+    int result;
+    struct chardev_status status;
+    struct chardev_size_range range;
+    struct chardev_info info;
+
+    switch (ioctl_num)
+    {
+    case CHARDEV_IOC_FLUSH:
+        flush_queue(queue);
+        return SUCCESS;
+    case CHARDEV_IOC_DROP_OLDEST:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        return drop_oldest(queue, value);
+    case CHARDEV_IOC_DROP_NEWEST:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        return drop_newest(queue, value);
+    case CHARDEV_IOC_QUEUE_LEN:
+        if (put_user((__u32)queue_length(queue), (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        return SUCCESS;
+    case CHARDEV_IOC_IS_EMPTY:
+        if (put_user((__u32)(queue_length(queue) == 0), (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        return SUCCESS;
+    case CHARDEV_IOC_PEEK_LEN:
+        length = peek_length(queue);
+        if (length < 0)
+            return length;
+        if (put_user((__u32)length, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        return SUCCESS;
+    case CHARDEV_IOC_RESET_STATS:
+        reset_stats(queue);
+        return SUCCESS;
+    case CHARDEV_IOC_SET_FULL_ERRNO:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        return set_full_errno(queue, value);
+    case CHARDEV_IOC_SET_NOTIFY_WATERMARK:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        set_notify_watermark(queue, value);
+        return SUCCESS;
+    case CHARDEV_IOC_MY_STATS:
+        return get_my_stats(handle, (struct chardev_my_stats __user *)ioctl_param);
+    case CHARDEV_IOC_OPEN_COUNT:
+        if (put_user((__u32)READ_ONCE(queue->open_count), (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        return SUCCESS;
+    case CHARDEV_IOC_HIGH_WATER:
+        if (put_user((__u32)atomic64_read(&queue->stats.high_water), (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        return SUCCESS;
+    case CHARDEV_IOC_SIZE_RANGE:
+        size_range(queue, &range);
+        if (copy_to_user((struct chardev_size_range __user *)ioctl_param, &range, sizeof(range)))
+            return -EFAULT;
+        return SUCCESS;
+    case CHARDEV_IOC_INFO:
+        info.abi_version = CHARDEV_ABI_VERSION;
+        info.max_messages_ceiling = MAX_QUEUE_SIZE_CEILING;
+        info.max_len_ceiling = MAX_STRING_LENGTH_CEILING;
+        if (copy_to_user((struct chardev_info __user *)ioctl_param, &info, sizeof(info)))
+            return -EFAULT;
+        return SUCCESS;
+    case CHARDEV_IOC_STATUS:
+        get_status(queue, &status);
+        if (copy_to_user((struct chardev_status __user *)ioctl_param, &status, sizeof(status)))
+            return -EFAULT;
+        return SUCCESS;
+    case CHARDEV_IOC_FREE_SLOTS:
+        if (put_user((__u32)free_slots(queue), (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        return SUCCESS;
+    case CHARDEV_IOC_HEAD_AGE:
+        result = head_age(queue, &bytes);
+        if (result < 0)
+            return result;
+        if (put_user(bytes, (__u64 __user *)ioctl_param))
+            return -EFAULT;
+        return SUCCESS;
+    case CHARDEV_IOC_GENERATION:
+        if (put_user(generation(queue), (__u64 __user *)ioctl_param))
+            return -EFAULT;
+        return SUCCESS;
+    case CHARDEV_IOC_BYTES_QUEUED:
+        if (put_user(bytes_queued(queue), (__u64 __user *)ioctl_param))
+            return -EFAULT;
+        return SUCCESS;
+    case CHARDEV_IOC_PEEK:
+        return device_peek(queue, (struct chardev_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_PEEK_AT:
+        return device_peek_at(queue, (struct chardev_index_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_ROTATE:
+        return device_rotate(queue, (struct chardev_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_SET_OVERWRITE:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        set_overwrite(queue, value != 0);
+        return SUCCESS;
+    case CHARDEV_IOC_SET_BROADCAST:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        set_broadcast(queue, value != 0);
+        return SUCCESS;
+    case CHARDEV_IOC_REWIND:
+        return rewind_handle(queue, handle);
+    case CHARDEV_IOC_SET_STREAM:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        set_stream(handle, value != 0);
+        return SUCCESS;
+    case CHARDEV_IOC_SET_RATE:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        set_rate(handle, value);
+        return SUCCESS;
+    case CHARDEV_IOC_SET_MODE:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        return set_mode(queue, value);
+    case CHARDEV_IOC_DRAIN:
+        return device_drain(handle, (struct chardev_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_LOAD_TEMPLATE:
+        return device_load_template(queue, (struct chardev_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_SNAPSHOT:
+        return device_snapshot(queue, (struct chardev_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_WRITE_PRIO:
+        return device_write_prio(file, (struct chardev_prio_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_MOVE_TO:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        if (value >= num_devices)
+            return -ENODEV;
+        return move_messages(iminor(file_inode(file)), value);
+#ifdef CHARDEV_DEBUG
+    case CHARDEV_IOC_FAIL_ALLOC:
+        atomic_set(&fail_alloc, 1);
+        return SUCCESS;
+#endif
+    case CHARDEV_IOC_READ_FRAMED:
+        return device_read_framed(file, (struct chardev_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_WRITE_TYPED:
+        return device_write_typed(file, (struct chardev_typed_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_READ_TYPED:
+        return device_read_typed(file, (struct chardev_typed_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_READ_REVERSE:
+        return device_read_reverse(file, (struct chardev_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_HANDOFF:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        if (value >= num_devices)
+            return -ENODEV;
+        return handoff_message(iminor(file_inode(file)), value);
+    case CHARDEV_IOC_SET_TEE:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        if (value >= num_devices && value != CHARDEV_TEE_OFF)
+            return -ENODEV;
+        return set_tee(iminor(file_inode(file)), value);
+    case CHARDEV_IOC_SET_LABEL:
+        return device_set_label(queue, (struct chardev_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_GET_LABEL:
+        return device_get_label(queue, (struct chardev_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_WRITE_IF_EMPTY:
+        return device_write_if_empty(file, (struct chardev_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_POP_IF_EQ:
+        return device_pop_if_eq(file, (struct chardev_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_WRITE_FRONT:
+        return device_write_front(file, (struct chardev_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_APPEND_ALL:
+        return device_append_all(queue, (struct chardev_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_SET_PREFIX:
+        return device_set_prefix(queue, (struct chardev_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_BLOCK_WRITES:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        set_writes_blocked(queue, value != 0);
+        return SUCCESS;
+    case CHARDEV_IOC_FREEZE:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        set_frozen(queue, value != 0);
+        return SUCCESS;
+    case CHARDEV_IOC_SET_DELIM:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        if (value > 0xff)
+            return -EINVAL;
+        set_delimiter(queue, value);
+        return SUCCESS;
+    case CHARDEV_IOC_READ_CONCAT:
+        return device_read_concat(handle, (struct chardev_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_SET_BYTE_BUDGET:
+        if (get_user(bytes, (__u64 __user *)ioctl_param))
+            return -EFAULT;
+        return set_byte_budget(queue, bytes);
+    case CHARDEV_IOC_INJECT:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        return inject_errors(queue, value);
+    case CHARDEV_IOC_SET_STRICT:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        set_strict(queue, value != 0);
+        return SUCCESS;
+    case CHARDEV_IOC_SET_ALLOW_EMPTY:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        set_allow_empty(queue, value != 0);
+        return SUCCESS;
+    case CHARDEV_IOC_SET_DRAIN_ON_CLOSE:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        set_drain_on_close(queue, value != 0);
+        return SUCCESS;
+    case CHARDEV_IOC_SET_DEDUP:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        set_dedup(queue, value != 0);
+        return SUCCESS;
+    case CHARDEV_IOC_SET_TIMESTAMP:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        set_timestamp(queue, value != 0);
+        return SUCCESS;
+    case CHARDEV_IOC_READ_TIMEOUT:
+        return device_read_timeout(file, (struct chardev_timeout_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_READ_SEQ:
+        return device_read_seq(file, (struct chardev_seq_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_READ_TS:
+        return device_read_ts(file, (struct chardev_ts_buffer __user *)ioctl_param);
+    case CHARDEV_IOC_MAX_MESSAGES:
+        if (put_user((__u32)queue_capacity(queue), (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        return SUCCESS;
+    case CHARDEV_IOC_SET_CAPACITY:
+        if (get_user(value, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        return set_capacity(queue, value);
+    case CHARDEV_IOC_MAX_LEN:
+        if (put_user((__u32)max_string_length, (__u32 __user *)ioctl_param))
+            return -EFAULT;
+        return SUCCESS;
+    default:
+        printk(KERN_INFO "Sorry, this operation isn't supported\n");
+        return -EINVAL;
+    }
 }
 
 // This function is called when the module is loaded.
 int init_module(void)
 {
+    int i;
+    int result;
+
+    if (max_string_length < 1 || max_string_length > MAX_STRING_LENGTH_CEILING)
+    {
+        printk(KERN_ALERT "max_string_length must be between 1 and %d\n", MAX_STRING_LENGTH_CEILING);
+        return -EINVAL;
+    }
+    if (max_messages < 1 || max_messages > MAX_QUEUE_SIZE_CEILING)
+    {
+        printk(KERN_ALERT "max_messages must be between 1 and %d\n", MAX_QUEUE_SIZE_CEILING);
+        return -EINVAL;
+    }
+    if (num_devices < 1 || num_devices > NUM_DEVICES_CEILING)
+    {
+        printk(KERN_ALERT "num_devices must be between 1 and %d\n", NUM_DEVICES_CEILING);
+        return -EINVAL;
+    }
+
     // When the module is loaded, the device is created. An empty list of messages is created as well.
-    queue = create_queue();
+    queues = kcalloc(num_devices, sizeof(Queue *), GFP_KERNEL);
+    if (queues == NULL)
+        return -ENOMEM;
+    for (i = 0; i < num_devices; i++)
+    {
+        queues[i] = create_queue();
+        if (queues[i] == NULL)
+        {
+            result = -ENOMEM;
+            goto err_queues;
+        }
+    }
 
     Major = register_chrdev(0, DEVICE_NAME, &fops);
 
     if (Major < 0)
     {
         printk(KERN_ALERT "Registering char device failed with %d\n", Major);
-        return Major;
+        result = Major;
+        goto err_queues;
+    }
+
+    result = create_devices();
+    if (result < 0)
+    {
+        printk(KERN_ALERT "Creating sysfs devices failed with %d\n", result);
+        goto err_chrdev;
+    }
+
+    result = create_proc_entries();
+    if (result < 0)
+    {
+        printk(KERN_ALERT "Creating proc entries failed with %d\n", result);
+        goto err_devices;
     }
+    create_debugfs_entries();
 
     // Required for tests
     printk(KERN_INFO "I was assigned major number %d. To talk to\n", Major);
@@ -141,8 +2981,17 @@ int init_module(void)
     printk(KERN_INFO "Try various minor numbers. Try to cat and echo to\n");
     printk(KERN_INFO "the device file.\n");
     printk(KERN_INFO "Remove the device file and module when done.\n");
+    printk(KERN_INFO "There are %u independent devices, with minor numbers 0 to %u.\n", num_devices, num_devices - 1);
 
     return SUCCESS;
+
+err_devices:
+    destroy_devices();
+err_chrdev:
+    unregister_chrdev(Major, DEVICE_NAME);
+err_queues:
+    destroy_queues();
+    return result;
 }
 
 // This function is called when the module is unloaded.
@@ -151,19 +3000,32 @@ void cleanup_module(void)
     // printk(KERN_INFO "Cleaning up module\n");
 
     // Removing the module deallocates all messages, removes the list of messages and removes the device.
-    kfree(queue);
-    queue = NULL;
+    // The debugfs and proc entries and devices go first so nothing can reach the queues while they're freed.
+    // Every file has been closed by now, as `fops.owner` makes open files hold a reference to the module, so the
+    // per-open state was already freed by `device_release` and nothing can be reading or writing.
+    destroy_debugfs_entries();
+    destroy_proc_entries();
+    destroy_devices();
 
     // Unregister the device
     unregister_chrdev(Major, DEVICE_NAME);
+
+    destroy_queues();
 }
 
 // Called when a process tries to open the device file, like `cat /dev/chardev`.
 static int device_open(struct inode *inode, struct file *file)
 {
+    unsigned int minor = iminor(inode);
+    Handle *handle;
     // printk(KERN_INFO "Device opened\n");
 
-    try_module_get(THIS_MODULE);
+    if (minor >= num_devices)
+        return -ENODEV;
+    handle = open_handle(queues[minor], (file->f_mode & FMODE_READ) != 0, (file->f_mode & FMODE_WRITE) != 0);
+    if (handle == NULL)
+        return -ENOMEM;
+    file->private_data = handle;
 
     return SUCCESS;
 }
@@ -173,11 +3035,73 @@ static int device_release(struct inode *inode, struct file *file)
 {
     // printk(KERN_INFO "Device closed\n");
 
-    module_put(THIS_MODULE);
+    // The VFS already does this for files with `O_ASYNC`, but the queue outlives the file so make sure it's gone
+    device_fasync(-1, file, 0);
+    close_handle(file->private_data);
 
     return 0;
 }
 
+// Called when `O_ASYNC` is set or cleared with `fcntl(F_SETFL)`, and when a file with it set is closed.
+static int device_fasync(int fd, struct file *filp, int on)
+{
+    Handle *handle = filp->private_data;
+
+    return fasync_helper(fd, filp, on, &handle->queue->async);
+}
+
+// Called when a process maps the dev file, which gives a read only `struct chardev_stats_page` of its live counters.
+static int device_mmap(struct file *filp, struct vm_area_struct *vma)
+{
+    Handle *handle = filp->private_data;
+    struct chardev_stats_page *page = handle->queue->stats_page;
+
+    if (vma->vm_pgoff != 0 || vma->vm_end - vma->vm_start > PAGE_SIZE)
+        return -EINVAL;
+    // Only the driver writes the counters, and `mprotect` mustn't be able to change that
+    if (vma->vm_flags & VM_WRITE)
+        return -EPERM;
+    vm_flags_clear(vma, VM_MAYWRITE);
+
+    return remap_pfn_range(
+        vma, vma->vm_start, virt_to_phys(page) >> PAGE_SHIFT, vma->vm_end - vma->vm_start, vma->vm_page_prot);
+}
+
+// Called when a process polls the dev file, e.g. with `poll` or `epoll`.
+static __poll_t device_poll(struct file *filp, poll_table *wait)
+{
+    Handle *handle = filp->private_data;
+    Queue *queue = handle->queue;
+    __poll_t mask = 0;
+
+    // Enqueues wake `read_wait` and dequeues wake `write_wait`, so either can change the result
+    poll_wait(filp, &queue->read_wait, wait);
+    poll_wait(filp, &queue->write_wait, wait);
+
+    if (has_message(handle))
+        mask |= EPOLLIN | EPOLLRDNORM;
+    // Once there's nothing left to read and nothing open to write more, readers can stop waiting
+    else if (READ_ONCE(queue->writers) == 0 && !READ_ONCE(queue->frozen))
+        mask |= EPOLLHUP;
+    if (has_room(queue, 1, 1) || (READ_ONCE(queue->overwrite) && !READ_ONCE(queue->writes_blocked)))
+        mask |= EPOLLOUT | EPOLLWRNORM;
+
+    return mask;
+}
+
+// Called when a process seeks the dev file. The queue can't be seeked, but `lseek(fd, 0, SEEK_CUR)` returns the
+// number of queued messages as the "position". Any other seek returns -ESPIPE.
+static loff_t device_llseek(struct file *filp, loff_t offset, int whence)
+{
+    Handle *handle = filp->private_data;
+    Queue *queue = handle->queue;
+
+    if (whence == SEEK_CUR && offset == 0)
+        return queue_length(queue);
+
+    return -ESPIPE;
+}
+
 // Called when a process, which already opened the dev file, attempts to read from it.
 static ssize_t device_read(
     struct file *filp, // see include/linux/fs.h
@@ -185,72 +3109,340 @@ static ssize_t device_read(
     size_t length,     // length of the buffer
     loff_t *offset)
 {
-    char *item;
-    int item_length;
     // printk(KERN_INFO "Device read\n");
+    return read_message(filp, buffer, length, default_read_timeout(filp), NULL, NULL);
+}
+
+// Removes a message from the queue into a user space buffer, used by `read` and the read ioctls.
+// Waits at most `timeout` jiffies for a message, which can be `MAX_SCHEDULE_TIMEOUT` to wait forever.
+// If `timestamp` or `sequence` isn't NULL it is set to the message's timestamp or sequence number.
+static ssize_t read_message(
+    struct file *filp, char __user *buffer, size_t length, long timeout, u64 *timestamp, u64 *sequence)
+{
+    Handle *handle = filp->private_data;
+    Queue *queue = handle->queue;
+    Message *message;
+    ssize_t result;
+    int waited = 0;
 
     // Reading from the device returns one message, and removes this message from the kernel list.
     // If the list of messages is empty, the reader returns -EAGAIN.
+    // Unless the file has `O_NONBLOCK`, the reader instead waits for a message.
+    // `f_flags` is checked on every read, so this can be changed after opening with `fcntl(F_SETFL)`.
+    // A signal interrupts the wait with -ERESTARTSYS, which is seen as EINTR unless the handler has `SA_RESTART`.
+    // If the buffer is too small for the message, -EMSGSIZE is returned and the message is left in the list.
+    // In stream mode the buffer is instead filled with part of the message, and the rest left for the next read.
+    // With `CHARDEV_IOC_READ_TIMEOUT` the reader waits a limited time, returning -ETIMEDOUT if no message arrives.
+    // In broadcast mode each open file reads its own copy of every message.
+    // Files opened with `O_WRONLY` can't read, including with the read ioctls, and get -EBADF.
 
-    item = kmalloc(sizeof(char) * MAX_STRING_LENGTH, GFP_KERNEL);
-    item_length = dequeue(queue, item);
-    if (item_length < 0)
+    if (!handle->reader)
+        return -EBADF;
+
+    while ((message = dequeue(queue, handle, length)) == ERR_PTR(-EAGAIN))
     {
-        printk(KERN_INFO "Queue is empty\n");
-        return -EAGAIN;
+        if (timeout == 0)
+        {
+            printk(KERN_INFO "Queue is empty\n");
+            return waited ? -ETIMEDOUT : -EAGAIN;
+        }
+        // Another reader may take the message first, in which case we wait again for the remaining time
+        timeout = wait_event_interruptible_timeout(queue->read_wait, has_message(handle), timeout);
+        if (timeout < 0)
+            return -ERESTARTSYS;
+        waited = 1;
     }
+    if (IS_ERR(message))
+        return PTR_ERR(message);
     // printk(KERN_INFO "About to `copy_to_user`\n");
-    if (item_length < length)
-        length = item_length;
-    if (copy_to_user(buffer, item, length))
+    // `dequeue` unlinked the message (or made a copy) under the lock, so no other thread can change or free it here
+    result = message->length;
+    if (timestamp != NULL)
+        *timestamp = message->timestamp;
+    if (sequence != NULL)
+        *sequence = message->sequence;
+    // In stream mode a message only counts as read with its last part, though every part counts towards the bytes
+    if (copy_to_user(buffer, message->string, message->length))
     {
         printk(KERN_INFO "Failed to `copy_to_user`\n");
-        kfree(item);
-        return -EFAULT;
+        result = -EFAULT;
     }
+    else
+        count_read(handle, message->partial ? 0 : 1, result);
 
     // printk(KERN_INFO "Read from queue\n");
-    kfree(item);
+    kvfree(message);
+    // The message is gone either way
+    publish_stats(queue);
 
-    return length;
+    return result;
 }
 
 // Called when a process writes to dev file, e.g. `echo "Hello, World!" > /dev/chardev`.
 static ssize_t device_write(struct file *filp, const char *buffer, size_t length, loff_t *off)
 {
-    char *msg;
-    int result;
     // printk(KERN_INFO "Device write\n");
+    return write_message(filp, buffer, length, CHARDEV_DEFAULT_PRIORITY, CHARDEV_DEFAULT_TYPE, 0);
+}
+
+// Adds a message with the given priority and type to the queue, used by `write` and the write ioctls.
+// `flags` are the `ENQUEUE_*` flags, which are 0 for `write`.
+static ssize_t write_message(
+    struct file *filp, const char __user *buffer, size_t length, u8 priority, u16 type, int flags)
+{
+    Handle *handle = filp->private_data;
+    Queue *queue = handle->queue;
+    Message *message;
+    int result;
 
     // Writing to the device stores the message in kernel space and adds it to the list
     // if the message is below the maximum size, and the limit of the number of all messages stored in the kernel
     // wouldn't be surpassed with this message. If the message is too big, -EINVAL is returned,
     // and if the limit of the number of all messages was surpassed, -EBUSY is returned.
+    // Unless the file has `O_NONBLOCK`, the writer instead waits for space in the queue.
+    // As with reads, `O_NONBLOCK` is checked on every write rather than when the file is opened.
+    // Each call is exactly one message of `length` bytes, messages are never split or merged.
+    // Files opened with `O_RDONLY` can't write, including with `CHARDEV_IOC_WRITE_PRIO`, and get -EBADF.
 
-    if (length > MAX_STRING_LENGTH)
+    if (!handle->writer)
+        return -EBADF;
+    // With `CHARDEV_IOC_INJECT` this write fails regardless of the message or the state of the queue
+    result = take_injected_error(queue);
+    if (result < 0)
+        return result;
+    if (length > max_string_length)
     {
         printk(KERN_INFO "Message too long\n");
+        atomic64_inc(&queue->stats.rejected_too_long);
         return -EINVAL;
     }
-
+    // An empty write is a no-op rather than an empty message, so it returns 0 without using a slot, even if full.
+    // With `CHARDEV_IOC_SET_ALLOW_EMPTY` it's an empty message like any other, which a read returns 0 for.
+    if (length == 0 && !READ_ONCE(queue->allow_empty))
+        return 0;
     // Store the message in kernel space and add it to the list
-    msg = kmalloc(sizeof(char) * length, GFP_KERNEL);
-    if (copy_from_user(msg, buffer, length))
+    message = create_message(length);
+    if (message == NULL)
+        return -ENOMEM;
+    if (copy_from_user(message->string, buffer, length))
     {
         printk(KERN_INFO "Failed to copy from user\n");
-        kfree(msg);
+        kvfree(message);
         return -EFAULT;
     }
-    result = enqueue(queue, msg, length);
-    kfree(msg);
-
-    if (result != 0)
+    // With `CHARDEV_IOC_SET_STRICT`, a message of only whitespace is invalid
+    if (READ_ONCE(queue->strict) && is_blank(message))
     {
-        printk(KERN_INFO "Queue too long\n");
-        return -EBUSY;
+        printk(KERN_INFO "Message is blank\n");
+        kvfree(message);
+        return -EINVAL;
+    }
+    // With `CHARDEV_IOC_SET_PREFIX`, a message without the prefix is invalid
+    if (!has_prefix(queue, message))
+    {
+        printk(KERN_INFO "Message doesn't have the prefix\n");
+        kvfree(message);
+        return -EINVAL;
+    }
+    // With `CHARDEV_IOC_SET_RATE` writes beyond the rate limit return -EAGAIN, or wait unless `O_NONBLOCK`.
+    // Only valid messages take a token, and it's given back if the message isn't enqueued.
+    result = wait_for_rate(filp, 1);
+    if (result < 0)
+    {
+        kvfree(message);
+        return result;
+    }
+    message->priority = priority;
+    message->type = type;
+    // With `CHARDEV_IOC_WRITE_IF_EMPTY` the queue is checked to be empty under the same lock as the message is added
+    while ((result = enqueue(queue, message, flags)) < 0)
+    {
+        if (result == -EEXIST || result == -EINVAL)
+            break;
+        if ((filp->f_flags & O_NONBLOCK) || !could_fit(queue, 1, length))
+        {
+            printk(KERN_INFO "Queue too long\n");
+            atomic64_inc(&queue->stats.rejected_busy);
+            // Only a full queue fails with `CHARDEV_IOC_SET_FULL_ERRNO`'s error, blocked writes always get -EBUSY
+            result = result == -ENOSPC ? -READ_ONCE(queue->full_errno) : -EBUSY;
+            break;
+        }
+        if (wait_event_interruptible(queue->write_wait, has_room(queue, 1, length)))
+        {
+            result = -ERESTARTSYS;
+            break;
+        }
+    }
+    if (result < 0)
+    {
+        kvfree(message);
+        return_rate_tokens(handle, 1);
+        return result;
     }
 
     // printk(KERN_INFO "Item added to the queue\n");
+    count_written(handle, 1, length);
+    publish_stats(queue);
 
     return length;
 }
+
+// Returns the segments of a vectored read or write, and sets `segments` to how many there are.
+// `single` is used if the segment is a plain user buffer rather than an array of them.
+// Returns NULL if the segments aren't user space buffers, e.g. kernel pages from `splice` or a `kernel_write`, as
+// their descriptors would otherwise be mistaken for user pointers.
+static const struct iovec *iter_segments(struct iov_iter *iter, struct iovec *single, unsigned long *segments)
+{
+#if LINUX_VERSION_CODE >= KERNEL_VERSION(6, 0, 0)
+    if (iter_is_ubuf(iter))
+    {
+        single->iov_base = iter->ubuf;
+        single->iov_len = iov_iter_count(iter);
+        *segments = 1;
+        return single;
+    }
+#endif
+    if (!iter_is_iovec(iter))
+        return NULL;
+    *segments = iter->nr_segs;
+    return iter_iov(iter);
+}
+
+// Called when a process does a vectored read, e.g. with `readv`.
+// Each segment is filled with one message, stopping when the queue is empty or the next message doesn't fit.
+// Returns the number of messages read rather than bytes, as the lengths of the messages aren't reported.
+// If no message can be read the errors are the same as `read`, e.g. -EMSGSIZE if the first segment is too small.
+// Returns -EINVAL if the segments aren't user space buffers.
+static ssize_t device_read_iter(struct kiocb *iocb, struct iov_iter *to)
+{
+    struct file *filp = iocb->ki_filp;
+    struct iovec single;
+    const struct iovec *iov;
+    unsigned long segments;
+    unsigned long i;
+    ssize_t result;
+
+    iov = iter_segments(to, &single, &segments);
+    if (iov == NULL)
+        return -EINVAL;
+    for (i = 0; i < segments; i++)
+    {
+        // Only the first message is waited for
+        result = read_message(filp, iov[i].iov_base, iov[i].iov_len, i == 0 ? default_read_timeout(filp) : 0, NULL, NULL);
+        if (result < 0)
+            return i == 0 ? result : i;
+    }
+
+    return segments;
+}
+
+// Called when a process does a vectored write, e.g. with `writev`.
+// Each segment is a separate message, and either every one is added to the list or none are.
+// The errors are the same as `write`, with -EBUSY also returned if there are more segments than the queue can hold
+// and -EINVAL if the segments aren't user space buffers.
+static ssize_t device_write_iter(struct kiocb *iocb, struct iov_iter *from)
+{
+    struct file *filp = iocb->ki_filp;
+    Handle *handle = filp->private_data;
+    Queue *queue = handle->queue;
+    struct iovec single;
+    const struct iovec *iov;
+    unsigned long segments;
+    size_t total = iov_iter_count(from);
+    size_t length;
+    Message *message;
+    LIST_HEAD(messages);
+    unsigned long i;
+    int count = 0;
+    int result;
+
+    if (!handle->writer)
+        return -EBADF;
+    iov = iter_segments(from, &single, &segments);
+    if (iov == NULL)
+        return -EINVAL;
+    result = take_injected_error(queue);
+    if (result < 0)
+        return result;
+
+    for (i = 0; i < segments; i++)
+    {
+        if (iov[i].iov_len > max_string_length)
+        {
+            printk(KERN_INFO "Message too long\n");
+            atomic64_inc(&queue->stats.rejected_too_long);
+            return -EINVAL;
+        }
+    }
+
+    for (i = 0; i < segments; i++)
+    {
+        length = iov[i].iov_len;
+        // Like `write`, empty segments aren't messages unless empty messages are allowed
+        if (length == 0 && !READ_ONCE(queue->allow_empty))
+            continue;
+        message = create_message(length);
+        if (message == NULL)
+        {
+            free_messages(&messages);
+            return -ENOMEM;
+        }
+        list_add_tail(&message->list, &messages);
+        count++;
+        if (copy_from_iter(message->string, length, from) != length)
+        {
+            printk(KERN_INFO "Failed to copy from user\n");
+            free_messages(&messages);
+            return -EFAULT;
+        }
+        if (READ_ONCE(queue->strict) && is_blank(message))
+        {
+            printk(KERN_INFO "Message is blank\n");
+            free_messages(&messages);
+            return -EINVAL;
+        }
+        if (!has_prefix(queue, message))
+        {
+            printk(KERN_INFO "Message doesn't have the prefix\n");
+            free_messages(&messages);
+            return -EINVAL;
+        }
+    }
+    if (count == 0)
+        return 0;
+    result = wait_for_rate(filp, count);
+    if (result < 0)
+    {
+        free_messages(&messages);
+        return result;
+    }
+
+    while ((result = enqueue_all(queue, &messages, 0)) < 0)
+    {
+        if ((filp->f_flags & O_NONBLOCK) || !could_fit(queue, count, total))
+        {
+            printk(KERN_INFO "Queue too long\n");
+            atomic64_inc(&queue->stats.rejected_busy);
+            // Only a full queue fails with `CHARDEV_IOC_SET_FULL_ERRNO`'s error, blocked writes always get -EBUSY
+            result = result == -ENOSPC ? -READ_ONCE(queue->full_errno) : -EBUSY;
+            break;
+        }
+        if (wait_event_interruptible(queue->write_wait, has_room(queue, count, total)))
+        {
+            result = -ERESTARTSYS;
+            break;
+        }
+    }
+    // Like `write`, the rate limit's tokens are given back if the messages aren't enqueued
+    if (result < 0)
+    {
+        free_messages(&messages);
+        return_rate_tokens(handle, count);
+        return result;
+    }
+
+    count_written(handle, count, total);
+    publish_stats(queue);
+
+    return total;
+}
@@ -7,10 +7,223 @@ static int device_release(struct inode *, struct file *);
 static ssize_t device_read(struct file *, char *, size_t, loff_t *);
 static ssize_t device_write(struct file *, const char *, size_t, loff_t *);
 static long device_ioctl(struct file *file, unsigned int ioctl_num, unsigned long);
+static __poll_t device_poll(struct file *, struct poll_table_struct *);
+static loff_t device_llseek(struct file *, loff_t, int);
+static int device_fasync(int, struct file *, int);
+static int device_mmap(struct file *, struct vm_area_struct *);
+static ssize_t device_read_iter(struct kiocb *, struct iov_iter *);
+static ssize_t device_write_iter(struct kiocb *, struct iov_iter *);
+static ssize_t write_message(struct file *, const char __user *, size_t, __u8, __u16, int);
+static ssize_t read_message(struct file *, char __user *, size_t, long, __u64 *, __u64 *);
 
 #define SUCCESS 0
 #define DEVICE_NAME "chardev" // Dev name as it appears in /proc/devices
 
+// A user space buffer, used by ioctls which transfer a message
+struct chardev_buffer
+{
+    __u64 data;   // Address of the buffer
+    __u64 length; // Length of the buffer
+};
+
+// A message with a priority, used by `CHARDEV_IOC_WRITE_PRIO`
+struct chardev_prio_buffer
+{
+    __u64 data;    // Address of the message
+    __u64 length;  // Length of the message
+    __u8 priority; // Higher priorities are read first in `CHARDEV_MODE_PRIORITY`
+};
+
+// A message and its type, used by `CHARDEV_IOC_WRITE_TYPED` and as a filter by `CHARDEV_IOC_READ_TYPED`
+struct chardev_typed_buffer
+{
+    __u64 data;   // Address of the buffer
+    __u64 length; // Length of the buffer
+    __u16 type;   // Messages written another way have `CHARDEV_DEFAULT_TYPE`
+};
+
+// A user space buffer and the time its message was enqueued, used by `CHARDEV_IOC_READ_TS`
+struct chardev_ts_buffer
+{
+    __u64 data;      // Address of the buffer
+    __u64 length;    // Length of the buffer
+    __u64 timestamp; // Set to the `ktime_get_ns` when the message was enqueued, or 0 if timestamps were off
+};
+
+// A user space buffer and the sequence number of its message, used by `CHARDEV_IOC_READ_SEQ`
+struct chardev_seq_buffer
+{
+    __u64 data;     // Address of the buffer
+    __u64 length;   // Length of the buffer
+    __u64 sequence; // Increases by 1 with each message enqueued, restarting from 0 with `CHARDEV_IOC_RESET_STATS`
+};
+
+// A user space buffer and how long to wait for a message, used by `CHARDEV_IOC_READ_TIMEOUT`
+struct chardev_timeout_buffer
+{
+    __u64 data;       // Address of the buffer
+    __u64 length;     // Length of the buffer
+    __u32 timeout_ms; // 0 doesn't wait, like `O_NONBLOCK`
+};
+
+// A user space buffer and the position of a queued message, used by `CHARDEV_IOC_PEEK_AT`
+struct chardev_index_buffer
+{
+    __u64 data;   // Address of the buffer
+    __u64 length; // Length of the buffer
+    __u32 index;  // 0 is the oldest message, whatever the mode
+};
+
+// The live counters of a device, which `mmap` maps read only so they can be polled without system calls.
+// Every field is naturally aligned, so there's no padding.
+struct chardev_stats_page
+{
+    __u32 queue_len;        // Number of queued messages
+    __u32 high_water;       // Most messages ever queued at once
+    __u64 bytes_queued;     // Total length of the queued messages
+    __u64 messages_written; // Messages written since the statistics were last reset
+    __u64 messages_read;    // Messages read since the statistics were last reset
+};
+
+// The state of a device at one moment, returned by `CHARDEV_IOC_STATUS`
+struct chardev_status
+{
+    __u32 len;          // Number of queued messages
+    __u32 free;         // How many more messages fit, so `len + free == max_messages`
+    __u64 bytes;        // Total length of the queued messages
+    __u32 max_messages; // The capacity, which `CHARDEV_IOC_SET_CAPACITY` may have changed from the module parameter
+    __u32 max_len;      // Maximum length of a message
+};
+
+// The lengths of the shortest and longest queued messages, returned by `CHARDEV_IOC_SIZE_RANGE`. Both are 0 if the
+// queue is empty.
+struct chardev_size_range
+{
+    __u32 min;
+    __u32 max;
+};
+
+// What the module was built with, returned by `CHARDEV_IOC_INFO`
+struct chardev_info
+{
+    __u32 abi_version;          // `CHARDEV_ABI_VERSION`
+    __u32 max_messages_ceiling; // Largest allowed `max_messages` or capacity
+    __u32 max_len_ceiling;      // Largest allowed `max_string_length`
+};
+
+// The counters of one open file, returned by `CHARDEV_IOC_MY_STATS`. They start from 0 when the file is opened.
+struct chardev_my_stats
+{
+    __u64 bytes_written;    // Total length of the messages written
+    __u64 bytes_read;       // Total length of the messages read, or parts of them in stream mode
+    __u64 messages_written; // Messages written
+    __u64 messages_read;    // Messages read
+};
+
+// ioctl commands, these must match the ones in `tests/main.rs`
+#define CHARDEV_IOC_MAGIC 'c'
+#define CHARDEV_IOC_FLUSH _IO(CHARDEV_IOC_MAGIC, 0)                                         // Drop every queued message
+#define CHARDEV_IOC_QUEUE_LEN _IOR(CHARDEV_IOC_MAGIC, 1, __u32)                             // Get the number of queued messages
+#define CHARDEV_IOC_PEEK _IOW(CHARDEV_IOC_MAGIC, 2, struct chardev_buffer)                  // Copy the next message without removing it
+#define CHARDEV_IOC_SET_OVERWRITE _IOW(CHARDEV_IOC_MAGIC, 3, __u32)                         // Evict the oldest message when full
+#define CHARDEV_IOC_MAX_MESSAGES _IOR(CHARDEV_IOC_MAGIC, 4, __u32)                          // Get the capacity, the maximum number of messages
+#define CHARDEV_IOC_MAX_LEN _IOR(CHARDEV_IOC_MAGIC, 5, __u32)                               // Get the maximum length of a message
+#define CHARDEV_IOC_SET_MODE _IOW(CHARDEV_IOC_MAGIC, 6, __u32)                              // Set the order messages are read in
+#define CHARDEV_IOC_WRITE_PRIO _IOW(CHARDEV_IOC_MAGIC, 7, struct chardev_prio_buffer)       // Write a message with a priority
+#define CHARDEV_IOC_SET_TIMESTAMP _IOW(CHARDEV_IOC_MAGIC, 8, __u32)                         // Record when messages are enqueued
+#define CHARDEV_IOC_READ_TS _IOWR(CHARDEV_IOC_MAGIC, 9, struct chardev_ts_buffer)           // Read a message and its timestamp
+#define CHARDEV_IOC_BYTES_QUEUED _IOR(CHARDEV_IOC_MAGIC, 10, __u64)                         // Get the total length of queued messages
+#define CHARDEV_IOC_FREE_SLOTS _IOR(CHARDEV_IOC_MAGIC, 11, __u32)                           // Get how many more messages fit
+#define CHARDEV_IOC_DRAIN _IOW(CHARDEV_IOC_MAGIC, 12, struct chardev_buffer)                // Remove every message at once
+#define CHARDEV_IOC_SET_CAPACITY _IOW(CHARDEV_IOC_MAGIC, 13, __u32)                         // Set the maximum number of messages
+#define CHARDEV_IOC_READ_TIMEOUT _IOW(CHARDEV_IOC_MAGIC, 14, struct chardev_timeout_buffer) // Read, waiting a limited time
+#define CHARDEV_IOC_SET_BROADCAST _IOW(CHARDEV_IOC_MAGIC, 15, __u32)                        // Give every open file a copy of every message
+#define CHARDEV_IOC_HIGH_WATER _IOR(CHARDEV_IOC_MAGIC, 16, __u32)                           // Get the most messages ever queued at once
+#define CHARDEV_IOC_RESET_STATS _IO(CHARDEV_IOC_MAGIC, 17)                                  // Zero the statistics, including the high water mark
+#define CHARDEV_IOC_SET_STREAM _IOW(CHARDEV_IOC_MAGIC, 18, __u32)                           // Let reads from this open file consume part of a message
+#define CHARDEV_IOC_SET_RATE _IOW(CHARDEV_IOC_MAGIC, 19, __u32)                             // Limit writes from this open file to messages per second
+#define CHARDEV_IOC_DROP_OLDEST _IOW(CHARDEV_IOC_MAGIC, 20, __u32)                          // Drop up to N of the oldest messages, returning how many
+#define CHARDEV_IOC_DROP_NEWEST _IOW(CHARDEV_IOC_MAGIC, 21, __u32)                          // Drop up to N of the newest messages, returning how many
+#define CHARDEV_IOC_SET_DEDUP _IOW(CHARDEV_IOC_MAGIC, 22, __u32)                            // Drop writes identical to the newest message
+#define CHARDEV_IOC_MOVE_TO _IOW(CHARDEV_IOC_MAGIC, 23, __u32)                              // Move every message to the end of another device
+#define CHARDEV_IOC_OPEN_COUNT _IOR(CHARDEV_IOC_MAGIC, 24, __u32)                           // Get the number of open files of the device
+#define CHARDEV_IOC_SET_STRICT _IOW(CHARDEV_IOC_MAGIC, 25, __u32)                           // Reject messages of only whitespace
+#define CHARDEV_IOC_READ_SEQ _IOWR(CHARDEV_IOC_MAGIC, 26, struct chardev_seq_buffer)        // Read a message and its sequence number
+#define CHARDEV_IOC_SET_LABEL _IOW(CHARDEV_IOC_MAGIC, 27, struct chardev_buffer)            // Set the label of the device
+#define CHARDEV_IOC_GET_LABEL _IOW(CHARDEV_IOC_MAGIC, 28, struct chardev_buffer)            // Copy the label of the device, returning its length
+#define CHARDEV_IOC_PEEK_LEN _IOR(CHARDEV_IOC_MAGIC, 29, __u32)                             // Get the length of the next message without removing it
+#define CHARDEV_IOC_INJECT _IOW(CHARDEV_IOC_MAGIC, 30, __u32)                               // Make the next write fail, for testing error handling
+#define CHARDEV_IOC_WRITE_IF_EMPTY _IOW(CHARDEV_IOC_MAGIC, 31, struct chardev_buffer)       // Write a message only if the queue is empty
+#define CHARDEV_IOC_SET_BYTE_BUDGET _IOW(CHARDEV_IOC_MAGIC, 32, __u64)                      // Set the maximum total length of queued messages, 0 for no limit
+#define CHARDEV_IOC_SET_DELIM _IOW(CHARDEV_IOC_MAGIC, 33, __u32)                            // Set the byte put between messages by `CHARDEV_IOC_READ_CONCAT`
+#define CHARDEV_IOC_READ_CONCAT _IOW(CHARDEV_IOC_MAGIC, 34, struct chardev_buffer)          // Remove every message at once, joined by the delimiter
+#define CHARDEV_IOC_FREEZE _IOW(CHARDEV_IOC_MAGIC, 35, __u32)                               // Make reads act as if the queue is empty
+#define CHARDEV_IOC_BLOCK_WRITES _IOW(CHARDEV_IOC_MAGIC, 36, __u32)                         // Make writes act as if the queue is full
+#define CHARDEV_IOC_SET_PREFIX _IOW(CHARDEV_IOC_MAGIC, 37, struct chardev_buffer)           // Only accept messages starting with a prefix
+#define CHARDEV_IOC_SET_TEE _IOW(CHARDEV_IOC_MAGIC, 38, __u32)                              // Copy every message enqueued to another device
+#define CHARDEV_IOC_PEEK_AT _IOW(CHARDEV_IOC_MAGIC, 39, struct chardev_index_buffer)        // Copy the message at a position without removing it
+#define CHARDEV_IOC_ROTATE _IOW(CHARDEV_IOC_MAGIC, 40, struct chardev_buffer)               // Copy the oldest message and move it to the end
+#define CHARDEV_IOC_SET_ALLOW_EMPTY _IOW(CHARDEV_IOC_MAGIC, 41, __u32)                      // Enqueue empty writes as empty messages
+#define CHARDEV_IOC_SNAPSHOT _IOW(CHARDEV_IOC_MAGIC, 42, struct chardev_buffer)             // Copy every message at once without removing them
+#define CHARDEV_IOC_MY_STATS _IOR(CHARDEV_IOC_MAGIC, 43, struct chardev_my_stats)           // Get the counters of this open file
+#define CHARDEV_IOC_SET_NOTIFY_WATERMARK _IOW(CHARDEV_IOC_MAGIC, 44, __u32)                 // Only send `SIGIO` once this many messages are queued
+#define CHARDEV_IOC_LOAD_TEMPLATE _IOW(CHARDEV_IOC_MAGIC, 45, struct chardev_buffer)        // Replace every message with those in a buffer
+#define CHARDEV_IOC_HEAD_AGE _IOR(CHARDEV_IOC_MAGIC, 46, __u64)                             // Get how many nanoseconds ago the oldest message was enqueued
+#define CHARDEV_IOC_HANDOFF _IOW(CHARDEV_IOC_MAGIC, 47, __u32)                              // Move the next message to the end of another device
+#define CHARDEV_IOC_FAIL_ALLOC _IO(CHARDEV_IOC_MAGIC, 48)                                   // Make the next message allocation fail, only built with `DEBUG=1`
+#define CHARDEV_IOC_READ_FRAMED _IOW(CHARDEV_IOC_MAGIC, 49, struct chardev_buffer)          // Read a message after its length
+#define CHARDEV_IOC_STATUS _IOR(CHARDEV_IOC_MAGIC, 50, struct chardev_status)               // Get the length, free slots and limits at once
+#define CHARDEV_IOC_WRITE_TYPED _IOW(CHARDEV_IOC_MAGIC, 51, struct chardev_typed_buffer)    // Write a message with a type
+#define CHARDEV_IOC_READ_TYPED _IOW(CHARDEV_IOC_MAGIC, 52, struct chardev_typed_buffer)     // Read the oldest message of a type, leaving the others
+#define CHARDEV_IOC_IS_EMPTY _IOR(CHARDEV_IOC_MAGIC, 53, __u32)                             // Get whether no messages are queued, 1 or 0
+#define CHARDEV_IOC_SIZE_RANGE _IOR(CHARDEV_IOC_MAGIC, 54, struct chardev_size_range)       // Get the lengths of the shortest and longest messages
+#define CHARDEV_IOC_SET_FULL_ERRNO _IOW(CHARDEV_IOC_MAGIC, 55, __u32)                       // Set what writes to a full queue fail with, `EBUSY` or `ENOSPC`
+#define CHARDEV_IOC_GENERATION _IOR(CHARDEV_IOC_MAGIC, 56, __u64)                           // Get how many times the queue was cleared or had messages dropped
+#define CHARDEV_IOC_READ_REVERSE _IOW(CHARDEV_IOC_MAGIC, 57, struct chardev_buffer)         // Read the newest message, whatever the mode
+#define CHARDEV_IOC_APPEND_ALL _IOW(CHARDEV_IOC_MAGIC, 58, struct chardev_buffer)           // Append the same bytes to every queued message
+#define CHARDEV_IOC_INFO _IOR(CHARDEV_IOC_MAGIC, 59, struct chardev_info)                   // Get the ABI version and the ceilings of the limits
+#define CHARDEV_IOC_SET_DRAIN_ON_CLOSE _IOW(CHARDEV_IOC_MAGIC, 60, __u32)                   // Set whether the last close flushes the queue
+#define CHARDEV_IOC_WRITE_FRONT _IOW(CHARDEV_IOC_MAGIC, 61, struct chardev_buffer)          // Write a message before the oldest, so it's read next
+#define CHARDEV_IOC_REWIND _IO(CHARDEV_IOC_MAGIC, 62)                                       // In broadcast mode, read again from the oldest message still queued
+#define CHARDEV_IOC_POP_IF_EQ _IOW(CHARDEV_IOC_MAGIC, 63, struct chardev_buffer)            // Remove the next message only if it equals the given bytes
+
+// `CHARDEV_IOC_DRAIN` and `CHARDEV_IOC_SNAPSHOT` write each message as a native endian `__u32` length followed by the
+// message, which is also the format `CHARDEV_IOC_LOAD_TEMPLATE` reads
+
+// In broadcast mode each open file reads from its own position, so ioctls which look at or remove messages regardless
+// of it fail with `EINVAL`. These are `CHARDEV_IOC_PEEK`, `CHARDEV_IOC_PEEK_LEN`, `CHARDEV_IOC_PEEK_AT`,
+// `CHARDEV_IOC_SNAPSHOT`, `CHARDEV_IOC_DRAIN`, `CHARDEV_IOC_READ_CONCAT`, `CHARDEV_IOC_READ_TYPED`,
+// `CHARDEV_IOC_READ_REVERSE`, `CHARDEV_IOC_POP_IF_EQ`, `CHARDEV_IOC_ROTATE` and `CHARDEV_IOC_WRITE_FRONT`
+
+// `CHARDEV_IOC_GENERATION` is increased once by each `CHARDEV_IOC_FLUSH`, `CHARDEV_IOC_LOAD_TEMPLATE` and last close
+// with `CHARDEV_IOC_SET_DRAIN_ON_CLOSE`, even if the queue was empty, and by each `CHARDEV_IOC_DROP_OLDEST` or
+// `CHARDEV_IOC_DROP_NEWEST` which drops at least one message. Reads never change it, including `CHARDEV_IOC_DRAIN` and
+// `CHARDEV_IOC_READ_CONCAT`, and neither does `CHARDEV_IOC_MOVE_TO`
+
+// `CHARDEV_IOC_READ_FRAMED` writes a little endian `__u32` length followed by the message, so the buffer must have room
+// for both
+
+// Modes for `CHARDEV_IOC_SET_MODE`
+#define CHARDEV_MODE_FIFO 0     // Read the oldest message first (default)
+#define CHARDEV_MODE_LIFO 1     // Read the newest message first
+#define CHARDEV_MODE_PRIORITY 2 // Read the highest priority message first, oldest first within a priority
+
+// Errors for `CHARDEV_IOC_INJECT`, if more than one is set the first listed here is used
+#define CHARDEV_INJECT_EBUSY (1 << 0)
+#define CHARDEV_INJECT_EINVAL (1 << 1)
+#define CHARDEV_INJECT_ENOMEM (1 << 2)
+
+#define CHARDEV_ABI_VERSION 10 // Increased whenever ioctls are added or their behaviour changes, so callers can check what's supported
+
+#define CHARDEV_TEE_OFF 0xFFFFFFFF // Target of `CHARDEV_IOC_SET_TEE` which stops copying messages
+
+#define CHARDEV_DEFAULT_PRIORITY 0 // Priority of messages written with `write`
+#define CHARDEV_DEFAULT_TYPE 0     // Type of messages written with `write`
+
+#define CHARDEV_LABEL_MAX 32  // Maximum length of a label set with `CHARDEV_IOC_SET_LABEL`, which defaults to empty
+#define CHARDEV_PREFIX_MAX 16 // Maximum length of a prefix set with `CHARDEV_IOC_SET_PREFIX`, which defaults to empty
+#define CHARDEV_SUFFIX_MAX 64 // Maximum length of a suffix appended with `CHARDEV_IOC_APPEND_ALL`
+
 // Global variables are declared as static, so are global within the file.
 struct cdev *my_cdev;
 dev_t dev_num;
@@ -18,8 +231,15 @@ dev_t dev_num;
 static int Major; // Major number assigned to our device driver
 
 static struct file_operations fops = {
+    .owner = THIS_MODULE, // Open files hold a reference to the module, so it can't be unloaded while they're used
     .read = device_read,
+    .read_iter = device_read_iter,
     .write = device_write,
+    .write_iter = device_write_iter,
     .open = device_open,
     .unlocked_ioctl = device_ioctl,
+    .poll = device_poll,
+    .llseek = device_llseek,
+    .fasync = device_fasync,
+    .mmap = device_mmap,
     .release = device_release};
@@ -2,8 +2,8 @@
 
 # - Cleans up the module and device file
 # - Compiles the kernel module
-# - Loads the kernel module
-# - Creates the device file
+# - Loads the kernel module, passing any arguments as module parameters, e.g. `max_messages=10`
+# - Creates the device files, `/dev/chardev` and `/dev/chardevN` for each device
 
 set -eu
 
@@ -14,13 +14,26 @@ make clean
 make
 
 echo "Loading the module..."
-sudo insmod charDeviceDriver.ko
+sudo insmod charDeviceDriver.ko "$@"
 
-echo "Creating the device file..."
+echo "Creating the device files..."
 # Sleep just in case
 MAJOR_NUMBER=$(dmesg | tac | grep -Pom 1 'mknod \/dev\/chardev c \K\d+')
 echo "Major number: \`$MAJOR_NUMBER\`"
-sudo mknod /dev/chardev c "$MAJOR_NUMBER" 0
-echo "Device file created: \`/dev/chardev\`"
-sudo chown "$USER" /dev/chardev
-echo "Device file ownership changed to \`$USER\`"
+NUM_DEVICES=$(cat /sys/module/charDeviceDriver/parameters/num_devices)
+echo "Number of devices: \`$NUM_DEVICES\`"
+
+create_device_file() {
+    # udev may have already created `/dev/chardevN` for the sysfs devices
+    if [ ! -e "$1" ]; then
+        sudo mknod "$1" c "$MAJOR_NUMBER" "$2"
+        echo "Device file created: \`$1\`"
+    fi
+    sudo chown "$USER" "$1"
+    echo "Device file ownership changed to \`$USER\`"
+}
+
+create_device_file /dev/chardev 0
+for ((i = 0; i < NUM_DEVICES; i++)); do
+    create_device_file "/dev/chardev$i" "$i"
+done
@@ -0,0 +1,30 @@
+#!/usr/bin/env bash
+
+# Repeatedly unloads the module with messages still queued, checking each reload starts empty
+# - Takes the number of reloads, defaulting to 10, then any module parameters to pass to `build.sh` on every load
+# - `DEBUG` is passed on to `build.sh` through the environment
+# - Reports any leaks found by kmemleak, if it is enabled
+
+set -euo pipefail
+
+RELOADS=${1:-10}
+shift || true
+KMEMLEAK=/sys/kernel/debug/kmemleak
+
+./scripts/build.sh "$@"
+
+for ((i = 0; i < RELOADS; i++)); do
+    echo "Reload $((i + 1)) of $RELOADS..."
+    sudo cargo test --test main -- --ignored --exact test_load_unload_with_residual
+    # Unloads the module with the queue full, then loads it again
+    ./scripts/build.sh "$@"
+    sudo cargo test --test main -- --ignored --exact test_empty_after_reload
+done
+
+./scripts/stop.sh
+
+if sudo test -e "$KMEMLEAK"; then
+    echo "Checking for leaks..."
+    echo scan | sudo tee "$KMEMLEAK" >/dev/null
+    sudo cat "$KMEMLEAK"
+fi
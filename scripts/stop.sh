@@ -1,11 +1,11 @@
 #!/usr/bin/env bash
 
-# Removes the kernel module and the device file
+# Removes the kernel module and the device files
 
 set -euo pipefail
 
-echo "Removing device file..."
-sudo rm /dev/chardev || :
+echo "Removing device files..."
+sudo rm /dev/chardev /dev/chardev[0-9]* || :
 
 echo "Removing kernel module..."
 sudo rmmod charDeviceDriver.ko || :
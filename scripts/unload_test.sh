@@ -0,0 +1,17 @@
+#!/usr/bin/env bash
+
+# Unloads the module while writers keep opening the device, checking it only unloads between their files
+# - Checks the kernel log for an oops or BUG from the unload
+
+set -euo pipefail
+
+./scripts/build.sh
+
+sudo dmesg --clear
+sudo cargo test --test main -- --ignored --exact test_unload_with_writers
+if sudo dmesg | grep -E 'Oops|BUG'; then
+    echo "The kernel reported an error during the unload"
+    exit 1
+fi
+
+./scripts/stop.sh
@@ -1,16 +1,227 @@
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufRead, BufReader, Read, Write};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const DEVICE_PATH: &str = "/dev/chardev";
-const MAX_STRING_LENGTH: usize = 4096;
-const MAX_MESSAGES: usize = 1000;
+const SYSFS_PATH: &str = "/sys/class/chardev/chardev0";
+const PROC_STATS_PATH: &str = "/proc/chardev/stats";
+const DEBUGFS_DUMP_PATH: &str = "/sys/kernel/debug/chardev/dump";
 
-// Read up to a newline.
+// A user space buffer, used by ioctls which transfer a message. Matches `struct chardev_buffer`.
+#[repr(C)]
+struct ChardevBuffer {
+    data: u64,
+    length: u64,
+}
+
+impl ChardevBuffer {
+    fn new(buf: &mut [u8]) -> Self {
+        ChardevBuffer {
+            data: buf.as_mut_ptr() as u64,
+            length: buf.len() as u64,
+        }
+    }
+}
+
+// A message with a priority. Matches `struct chardev_prio_buffer`.
+#[repr(C)]
+struct ChardevPrioBuffer {
+    data: u64,
+    length: u64,
+    priority: u8,
+}
+
+// A message and its type. Matches `struct chardev_typed_buffer`.
+#[repr(C)]
+struct ChardevTypedBuffer {
+    data: u64,
+    length: u64,
+    r#type: u16,
+}
+
+// A user space buffer and the time its message was enqueued. Matches `struct chardev_ts_buffer`.
+#[repr(C)]
+struct ChardevTsBuffer {
+    data: u64,
+    length: u64,
+    timestamp: u64,
+}
+
+// A user space buffer and the sequence number of its message. Matches `struct chardev_seq_buffer`.
+#[repr(C)]
+struct ChardevSeqBuffer {
+    data: u64,
+    length: u64,
+    sequence: u64,
+}
+
+// A user space buffer and how long to wait for a message. Matches `struct chardev_timeout_buffer`.
+#[repr(C)]
+struct ChardevTimeoutBuffer {
+    data: u64,
+    length: u64,
+    timeout_ms: u32,
+}
+
+// A user space buffer and the position of a queued message. Matches `struct chardev_index_buffer`.
+#[repr(C)]
+struct ChardevIndexBuffer {
+    data: u64,
+    length: u64,
+    index: u32,
+}
+
+// The live counters of a device, mapped with `mmap`. Matches `struct chardev_stats_page`.
+#[repr(C)]
+struct ChardevStatsPage {
+    queue_len: u32,
+    high_water: u32,
+    bytes_queued: u64,
+    messages_written: u64,
+    messages_read: u64,
+}
+
+// The state of a device at one moment. Matches `struct chardev_status`.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct Status {
+    len: u32,
+    free: u32,
+    bytes: u64,
+    max_messages: u32,
+    max_len: u32,
+}
+
+// The lengths of the shortest and longest queued messages. Matches `struct chardev_size_range`.
+#[repr(C)]
+#[derive(Debug, Default, PartialEq)]
+struct ChardevSizeRange {
+    min: u32,
+    max: u32,
+}
+
+// What the module was built with. Matches `struct chardev_info`.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct ChardevInfo {
+    abi_version: u32,
+    max_messages_ceiling: u32,
+    max_len_ceiling: u32,
+}
+
+// The counters of one open file. Matches `struct chardev_my_stats`.
+#[repr(C)]
+#[derive(Debug, Default, PartialEq)]
+struct ChardevMyStats {
+    bytes_written: u64,
+    bytes_read: u64,
+    messages_written: u64,
+    messages_read: u64,
+}
+
+// ioctl commands, these must match the ones in `charDeviceDriver.h`
+const CHARDEV_IOC_MAGIC: u32 = b'c' as u32;
+const CHARDEV_IOC_FLUSH: libc::Ioctl = libc::_IO(CHARDEV_IOC_MAGIC, 0);
+const CHARDEV_IOC_QUEUE_LEN: libc::Ioctl = libc::_IOR::<u32>(CHARDEV_IOC_MAGIC, 1);
+const CHARDEV_IOC_PEEK: libc::Ioctl = libc::_IOW::<ChardevBuffer>(CHARDEV_IOC_MAGIC, 2);
+const CHARDEV_IOC_SET_OVERWRITE: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 3);
+const CHARDEV_IOC_MAX_MESSAGES: libc::Ioctl = libc::_IOR::<u32>(CHARDEV_IOC_MAGIC, 4);
+const CHARDEV_IOC_MAX_LEN: libc::Ioctl = libc::_IOR::<u32>(CHARDEV_IOC_MAGIC, 5);
+const CHARDEV_IOC_SET_MODE: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 6);
+const CHARDEV_IOC_WRITE_PRIO: libc::Ioctl = libc::_IOW::<ChardevPrioBuffer>(CHARDEV_IOC_MAGIC, 7);
+const CHARDEV_IOC_SET_TIMESTAMP: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 8);
+const CHARDEV_IOC_READ_TS: libc::Ioctl = libc::_IOWR::<ChardevTsBuffer>(CHARDEV_IOC_MAGIC, 9);
+const CHARDEV_IOC_BYTES_QUEUED: libc::Ioctl = libc::_IOR::<u64>(CHARDEV_IOC_MAGIC, 10);
+const CHARDEV_IOC_FREE_SLOTS: libc::Ioctl = libc::_IOR::<u32>(CHARDEV_IOC_MAGIC, 11);
+const CHARDEV_IOC_DRAIN: libc::Ioctl = libc::_IOW::<ChardevBuffer>(CHARDEV_IOC_MAGIC, 12);
+const CHARDEV_IOC_SET_CAPACITY: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 13);
+const CHARDEV_IOC_READ_TIMEOUT: libc::Ioctl =
+    libc::_IOW::<ChardevTimeoutBuffer>(CHARDEV_IOC_MAGIC, 14);
+const CHARDEV_IOC_SET_BROADCAST: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 15);
+const CHARDEV_IOC_HIGH_WATER: libc::Ioctl = libc::_IOR::<u32>(CHARDEV_IOC_MAGIC, 16);
+const CHARDEV_IOC_RESET_STATS: libc::Ioctl = libc::_IO(CHARDEV_IOC_MAGIC, 17);
+const CHARDEV_IOC_SET_STREAM: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 18);
+const CHARDEV_IOC_SET_RATE: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 19);
+const CHARDEV_IOC_DROP_OLDEST: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 20);
+const CHARDEV_IOC_DROP_NEWEST: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 21);
+const CHARDEV_IOC_SET_DEDUP: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 22);
+const CHARDEV_IOC_MOVE_TO: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 23);
+const CHARDEV_IOC_OPEN_COUNT: libc::Ioctl = libc::_IOR::<u32>(CHARDEV_IOC_MAGIC, 24);
+const CHARDEV_IOC_SET_STRICT: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 25);
+const CHARDEV_IOC_READ_SEQ: libc::Ioctl = libc::_IOWR::<ChardevSeqBuffer>(CHARDEV_IOC_MAGIC, 26);
+const CHARDEV_IOC_SET_LABEL: libc::Ioctl = libc::_IOW::<ChardevBuffer>(CHARDEV_IOC_MAGIC, 27);
+const CHARDEV_IOC_GET_LABEL: libc::Ioctl = libc::_IOW::<ChardevBuffer>(CHARDEV_IOC_MAGIC, 28);
+const CHARDEV_IOC_PEEK_LEN: libc::Ioctl = libc::_IOR::<u32>(CHARDEV_IOC_MAGIC, 29);
+const CHARDEV_IOC_INJECT: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 30);
+const CHARDEV_IOC_WRITE_IF_EMPTY: libc::Ioctl = libc::_IOW::<ChardevBuffer>(CHARDEV_IOC_MAGIC, 31);
+const CHARDEV_IOC_SET_BYTE_BUDGET: libc::Ioctl = libc::_IOW::<u64>(CHARDEV_IOC_MAGIC, 32);
+const CHARDEV_IOC_SET_DELIM: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 33);
+const CHARDEV_IOC_READ_CONCAT: libc::Ioctl = libc::_IOW::<ChardevBuffer>(CHARDEV_IOC_MAGIC, 34);
+const CHARDEV_IOC_FREEZE: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 35);
+const CHARDEV_IOC_BLOCK_WRITES: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 36);
+const CHARDEV_IOC_SET_PREFIX: libc::Ioctl = libc::_IOW::<ChardevBuffer>(CHARDEV_IOC_MAGIC, 37);
+const CHARDEV_IOC_SET_TEE: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 38);
+const CHARDEV_IOC_PEEK_AT: libc::Ioctl = libc::_IOW::<ChardevIndexBuffer>(CHARDEV_IOC_MAGIC, 39);
+const CHARDEV_IOC_ROTATE: libc::Ioctl = libc::_IOW::<ChardevBuffer>(CHARDEV_IOC_MAGIC, 40);
+const CHARDEV_IOC_SET_ALLOW_EMPTY: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 41);
+const CHARDEV_IOC_SNAPSHOT: libc::Ioctl = libc::_IOW::<ChardevBuffer>(CHARDEV_IOC_MAGIC, 42);
+const CHARDEV_IOC_MY_STATS: libc::Ioctl = libc::_IOR::<ChardevMyStats>(CHARDEV_IOC_MAGIC, 43);
+const CHARDEV_IOC_SET_NOTIFY_WATERMARK: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 44);
+const CHARDEV_IOC_LOAD_TEMPLATE: libc::Ioctl = libc::_IOW::<ChardevBuffer>(CHARDEV_IOC_MAGIC, 45);
+const CHARDEV_IOC_HEAD_AGE: libc::Ioctl = libc::_IOR::<u64>(CHARDEV_IOC_MAGIC, 46);
+const CHARDEV_IOC_HANDOFF: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 47);
+const CHARDEV_IOC_FAIL_ALLOC: libc::Ioctl = libc::_IO(CHARDEV_IOC_MAGIC, 48);
+const CHARDEV_IOC_READ_FRAMED: libc::Ioctl = libc::_IOW::<ChardevBuffer>(CHARDEV_IOC_MAGIC, 49);
+const CHARDEV_IOC_STATUS: libc::Ioctl = libc::_IOR::<Status>(CHARDEV_IOC_MAGIC, 50);
+const CHARDEV_IOC_WRITE_TYPED: libc::Ioctl =
+    libc::_IOW::<ChardevTypedBuffer>(CHARDEV_IOC_MAGIC, 51);
+const CHARDEV_IOC_READ_TYPED: libc::Ioctl = libc::_IOW::<ChardevTypedBuffer>(CHARDEV_IOC_MAGIC, 52);
+const CHARDEV_IOC_IS_EMPTY: libc::Ioctl = libc::_IOR::<u32>(CHARDEV_IOC_MAGIC, 53);
+const CHARDEV_IOC_SIZE_RANGE: libc::Ioctl = libc::_IOR::<ChardevSizeRange>(CHARDEV_IOC_MAGIC, 54);
+const CHARDEV_IOC_SET_FULL_ERRNO: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 55);
+const CHARDEV_IOC_GENERATION: libc::Ioctl = libc::_IOR::<u64>(CHARDEV_IOC_MAGIC, 56);
+const CHARDEV_IOC_READ_REVERSE: libc::Ioctl = libc::_IOW::<ChardevBuffer>(CHARDEV_IOC_MAGIC, 57);
+const CHARDEV_IOC_APPEND_ALL: libc::Ioctl = libc::_IOW::<ChardevBuffer>(CHARDEV_IOC_MAGIC, 58);
+const CHARDEV_IOC_INFO: libc::Ioctl = libc::_IOR::<ChardevInfo>(CHARDEV_IOC_MAGIC, 59);
+const CHARDEV_IOC_SET_DRAIN_ON_CLOSE: libc::Ioctl = libc::_IOW::<u32>(CHARDEV_IOC_MAGIC, 60);
+const CHARDEV_IOC_WRITE_FRONT: libc::Ioctl = libc::_IOW::<ChardevBuffer>(CHARDEV_IOC_MAGIC, 61);
+const CHARDEV_IOC_REWIND: libc::Ioctl = libc::_IO(CHARDEV_IOC_MAGIC, 62);
+const CHARDEV_IOC_POP_IF_EQ: libc::Ioctl = libc::_IOW::<ChardevBuffer>(CHARDEV_IOC_MAGIC, 63);
+
+const CHARDEV_INJECT_EBUSY: u32 = 1 << 0;
+const CHARDEV_INJECT_EINVAL: u32 = 1 << 1;
+const CHARDEV_INJECT_ENOMEM: u32 = 1 << 2;
+
+const CHARDEV_LABEL_MAX: usize = 32;
+
+// Defaults of the `max_messages` and `max_string_length` module parameters
+const MAX_MESSAGES: u32 = 1000;
+const MAX_STRING_LENGTH: u32 = 4096;
+
+const CHARDEV_TEE_OFF: u32 = u32::MAX;
+
+const CHARDEV_MODE_FIFO: u32 = 0;
+const CHARDEV_MODE_LIFO: u32 = 1;
+const CHARDEV_MODE_PRIORITY: u32 = 2;
+
+// Read whole messages until one ends with a newline, which is stripped.
+// A `BufReader` can't be used as it reads ahead, dropping any following messages, and reading a byte at a time
+// doesn't work as a read into a short buffer fails with EMSGSIZE.
 fn read_line(file: &mut File) -> io::Result<String> {
-    BufReader::new(file).lines().next().unwrap()
+    let mut line = Vec::new();
+    while line.last() != Some(&b'\n') {
+        line.extend(read_bytes(file)?);
+    }
+    line.pop();
+    Ok(String::from_utf8(line).unwrap())
 }
 
 // Write a string, appending a newline.
@@ -22,7 +233,7 @@ fn write_line(file: &mut File, line: &str) -> io::Result<()> {
 
 // Do a single read call. Not dependent on a trailing newline.
 fn read_str(file: &mut File) -> io::Result<String> {
-    let mut buf = [0; MAX_STRING_LENGTH];
+    let mut buf = vec![0; max_string_length(file)? as usize];
     let bytes = file.read(&mut buf)?;
     Ok(String::from_utf8(buf[..bytes].to_vec()).unwrap())
 }
@@ -34,392 +245,3764 @@ fn write_str(file: &mut File, line: &str) -> io::Result<()> {
 
 // Read bytes.
 fn read_bytes(file: &mut File) -> io::Result<Vec<u8>> {
-    let mut buf = [0; MAX_STRING_LENGTH];
+    let mut buf = vec![0; max_string_length(file)? as usize];
     let bytes = file.read(&mut buf)?;
     Ok(buf[..bytes].to_vec())
 }
 
+// Write each slice as a separate message with a single `writev` call, returning the total bytes written.
+fn write_vectored_messages(file: &mut File, messages: &[&[u8]]) -> io::Result<usize> {
+    let iovecs: Vec<libc::iovec> = messages
+        .iter()
+        .map(|message| libc::iovec {
+            iov_base: message.as_ptr() as *mut libc::c_void,
+            iov_len: message.len(),
+        })
+        .collect();
+    let result = unsafe { libc::writev(file.as_raw_fd(), iovecs.as_ptr(), iovecs.len() as i32) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(result as usize)
+}
+
+// Read a message into each buffer with a single `readv` call, returning the number of messages read.
+fn read_vectored_messages(file: &mut File, buffers: &mut [&mut [u8]]) -> io::Result<usize> {
+    let iovecs: Vec<libc::iovec> = buffers
+        .iter_mut()
+        .map(|buffer| libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buffer.len(),
+        })
+        .collect();
+    let result = unsafe { libc::readv(file.as_raw_fd(), iovecs.as_ptr(), iovecs.len() as i32) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(result as usize)
+}
+
+// Do a single read call into the given buffer.
+fn read_into(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    file.read(buf)
+}
+
 // Write bytes.
 fn write_bytes(file: &mut File, bytes: &[u8]) -> io::Result<()> {
     file.write_all(bytes)
 }
 
-// Open the device for read and write.
-fn open() -> File {
-    OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(DEVICE_PATH)
-        .unwrap()
+// Do an ioctl, turning a negative return value into the OS error.
+fn ioctl<T>(file: &File, request: libc::Ioctl, arg: *mut T) -> io::Result<libc::c_int> {
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), request, arg) };
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
 }
 
-#[test]
-fn test_device_exists() {
-    assert!(Path::new(DEVICE_PATH).exists());
+// The read only counters page of a device, unmapped when dropped.
+struct StatsMapping(*const ChardevStatsPage);
+
+impl StatsMapping {
+    fn new(file: &File) -> io::Result<Self> {
+        let address = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                std::mem::size_of::<ChardevStatsPage>(),
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if address == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(address as *const ChardevStatsPage))
+    }
+
+    // Read the counters, which the driver may change at any time.
+    fn read(&self) -> ChardevStatsPage {
+        unsafe { ptr::read_volatile(self.0) }
+    }
 }
 
-#[test]
-fn test_write_read_short() {
-    let mut file = open();
-    let line = "Hello, World!";
-    write_line(&mut file, line).unwrap();
-    assert_eq!(read_line(&mut file).unwrap(), line);
+impl Drop for StatsMapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(
+                self.0 as *mut libc::c_void,
+                std::mem::size_of::<ChardevStatsPage>(),
+            )
+        };
+    }
 }
 
-#[test]
-fn test_write_read_short_no_newline() {
-    let mut file = open();
-    let line = "Hello, World!";
-    write_str(&mut file, line).unwrap();
-    assert_eq!(read_str(&mut file).unwrap(), line);
+// Set or clear `O_NONBLOCK` on an open file.
+fn set_nonblocking(file: &mut File, on: bool) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if on {
+        flags | libc::O_NONBLOCK
+    } else {
+        flags & !libc::O_NONBLOCK
+    };
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
 }
 
-#[test]
-fn test_write_read_short_null_byte() {
-    let mut file = open();
-    let line = "Hello, World!\0";
-    write_str(&mut file, line).unwrap();
-    assert_eq!(read_str(&mut file).unwrap(), line);
+// Install a handler for `signal` which does nothing, without `SA_RESTART` so blocked calls fail with EINTR.
+fn ignore_signal_without_restart(signal: libc::c_int) {
+    extern "C" fn handle(_: libc::c_int) {}
+
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        assert_eq!(libc::sigaction(signal, &action, ptr::null_mut()), 0);
+    }
 }
 
-#[test]
-fn test_write_read_fifo_no_newline() {
-    let mut file = open();
-    for i in 0..10 {
-        let line = format!("Write {i}");
-        write_str(&mut file, &line).unwrap();
+// Set by the `SIGIO` handler installed by `enable_async`.
+static SIGIO_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+// Install a `SIGIO` handler which sets `SIGIO_RECEIVED`, and have `SIGIO` sent to this process when a message arrives.
+fn enable_async(file: &mut File) -> io::Result<()> {
+    extern "C" fn handle(_: libc::c_int) {
+        SIGIO_RECEIVED.store(true, Ordering::SeqCst);
     }
-    for i in 0..10 {
-        let line = format!("Write {i}");
-        assert_eq!(read_str(&mut file).unwrap(), line);
+
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGIO, &action, ptr::null_mut()) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::fcntl(file.as_raw_fd(), libc::F_SETOWN, libc::getpid()) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = libc::fcntl(file.as_raw_fd(), libc::F_GETFL);
+        if flags < 0 || libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags | libc::O_ASYNC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// Only send `SIGIO` when a message arrives if at least `n` messages are then queued.
+fn set_notify_watermark(file: &mut File, n: u32) -> io::Result<()> {
+    let mut value = n;
+    ioctl(file, CHARDEV_IOC_SET_NOTIFY_WATERMARK, &mut value)?;
+    Ok(())
+}
+
+// Make the next message allocation fail, which requires the module to be built with `DEBUG=1`.
+fn fail_next_alloc(file: &mut File) -> io::Result<()> {
+    ioctl(file, CHARDEV_IOC_FAIL_ALLOC, ptr::null_mut::<()>())?;
+    Ok(())
+}
+
+// Drop every queued message.
+fn flush(file: &mut File) -> io::Result<()> {
+    ioctl(file, CHARDEV_IOC_FLUSH, ptr::null_mut::<()>())?;
+    Ok(())
+}
+
+// Get whether no messages are queued, without the side effects a failing read may have.
+fn is_empty(file: &mut File) -> io::Result<bool> {
+    let mut empty = 0u32;
+    ioctl(file, CHARDEV_IOC_IS_EMPTY, &mut empty)?;
+    Ok(empty != 0)
+}
+
+// Get the lengths of the shortest and longest queued messages, both 0 if the queue is empty.
+fn size_range(file: &mut File) -> io::Result<ChardevSizeRange> {
+    let mut range = ChardevSizeRange::default();
+    ioctl(file, CHARDEV_IOC_SIZE_RANGE, &mut range)?;
+    Ok(range)
+}
+
+// Get the number of queued messages without consuming any.
+fn queue_len(file: &mut File) -> io::Result<u32> {
+    let mut len: u32 = 0;
+    ioctl(file, CHARDEV_IOC_QUEUE_LEN, &mut len)?;
+    Ok(len)
+}
+
+// Copy the next message without consuming it.
+fn peek_str(file: &mut File) -> io::Result<String> {
+    let mut buf = vec![0; max_string_length(file)? as usize];
+    let mut arg = ChardevBuffer::new(&mut buf);
+    let bytes = ioctl(file, CHARDEV_IOC_PEEK, &mut arg)? as usize;
+    Ok(String::from_utf8(buf[..bytes].to_vec()).unwrap())
+}
+
+// Copy the message `index` places from the oldest without consuming it, failing with ERANGE if there isn't one.
+fn peek_at(file: &mut File, index: u32) -> io::Result<String> {
+    let mut buf = vec![0; max_string_length(file)? as usize];
+    let mut arg = ChardevIndexBuffer {
+        data: buf.as_mut_ptr() as u64,
+        length: buf.len() as u64,
+        index,
+    };
+    let bytes = ioctl(file, CHARDEV_IOC_PEEK_AT, &mut arg)? as usize;
+    Ok(String::from_utf8(buf[..bytes].to_vec()).unwrap())
+}
+
+// Copy the oldest message and move it to the end of the queue.
+fn rotate(file: &mut File) -> io::Result<String> {
+    let mut buf = vec![0; max_string_length(file)? as usize];
+    let mut arg = ChardevBuffer::new(&mut buf);
+    let bytes = ioctl(file, CHARDEV_IOC_ROTATE, &mut arg)? as usize;
+    Ok(String::from_utf8(buf[..bytes].to_vec()).unwrap())
+}
+
+// Get the length of the next message without consuming it.
+fn peek_len(file: &mut File) -> io::Result<u32> {
+    let mut len: u32 = 0;
+    ioctl(file, CHARDEV_IOC_PEEK_LEN, &mut len)?;
+    Ok(len)
+}
+
+// Get the maximum number of messages the queue can hold, set by the `max_messages` module parameter.
+fn max_messages(file: &mut File) -> io::Result<u32> {
+    let mut max: u32 = 0;
+    ioctl(file, CHARDEV_IOC_MAX_MESSAGES, &mut max)?;
+    Ok(max)
+}
+
+// Get the maximum length of a message, set by the `max_string_length` module parameter.
+fn max_string_length(file: &mut File) -> io::Result<u32> {
+    let mut max: u32 = 0;
+    ioctl(file, CHARDEV_IOC_MAX_LEN, &mut max)?;
+    Ok(max)
+}
+
+// Set whether writing to a full queue evicts the oldest message instead of failing.
+fn set_overwrite(file: &mut File, enabled: bool) -> io::Result<()> {
+    let mut value = enabled as u32;
+    ioctl(file, CHARDEV_IOC_SET_OVERWRITE, &mut value)?;
+    Ok(())
+}
+
+fn set_broadcast(file: &mut File, enabled: bool) -> io::Result<()> {
+    let mut value = enabled as u32;
+    ioctl(file, CHARDEV_IOC_SET_BROADCAST, &mut value)?;
+    Ok(())
+}
+
+// In broadcast mode, read again from the oldest message still queued.
+fn rewind(file: &mut File) -> io::Result<()> {
+    ioctl(file, CHARDEV_IOC_REWIND, ptr::null_mut::<()>())?;
+    Ok(())
+}
+
+// Open two files which, in broadcast mode, each read every message.
+fn open_subscribers() -> (File, File) {
+    (open_nonblocking(), open_nonblocking())
+}
+
+// Set whether reads from this file can consume part of a message.
+fn set_stream_mode(file: &mut File, on: bool) -> io::Result<()> {
+    let mut value = on as u32;
+    ioctl(file, CHARDEV_IOC_SET_STREAM, &mut value)?;
+    Ok(())
+}
+
+// Limit writes from this file to `msgs_per_sec`, 0 for no limit.
+fn set_rate(file: &mut File, msgs_per_sec: u32) -> io::Result<()> {
+    let mut value = msgs_per_sec;
+    ioctl(file, CHARDEV_IOC_SET_RATE, &mut value)?;
+    Ok(())
+}
+
+fn set_mode(file: &mut File, mode: u32) -> io::Result<()> {
+    let mut value = mode;
+    ioctl(file, CHARDEV_IOC_SET_MODE, &mut value)?;
+    Ok(())
+}
+
+// Write a message with a priority, returning the number of bytes written.
+fn write_prio(file: &mut File, bytes: &[u8], prio: u8) -> io::Result<usize> {
+    let mut arg = ChardevPrioBuffer {
+        data: bytes.as_ptr() as u64,
+        length: bytes.len() as u64,
+        priority: prio,
+    };
+    Ok(ioctl(file, CHARDEV_IOC_WRITE_PRIO, &mut arg)? as usize)
+}
+
+// Write a message with a type, which `read_typed` can filter by.
+fn write_typed(file: &mut File, bytes: &[u8], r#type: u16) -> io::Result<usize> {
+    let mut arg = ChardevTypedBuffer {
+        data: bytes.as_ptr() as u64,
+        length: bytes.len() as u64,
+        r#type,
+    };
+    Ok(ioctl(file, CHARDEV_IOC_WRITE_TYPED, &mut arg)? as usize)
+}
+
+// Read the oldest message of a type, leaving messages of other types queued. Never waits.
+fn read_typed(file: &mut File, r#type: u16) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; max_string_length(file)? as usize];
+    let mut arg = ChardevTypedBuffer {
+        data: buf.as_mut_ptr() as u64,
+        length: buf.len() as u64,
+        r#type,
+    };
+    let bytes = ioctl(file, CHARDEV_IOC_READ_TYPED, &mut arg)? as usize;
+    buf.truncate(bytes);
+    Ok(buf)
+}
+
+// Read the newest message, without changing the order normal reads use. Never waits.
+fn read_reverse(file: &mut File) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; max_string_length(file)? as usize];
+    let mut arg = ChardevBuffer::new(&mut buf);
+    let bytes = ioctl(file, CHARDEV_IOC_READ_REVERSE, &mut arg)? as usize;
+    buf.truncate(bytes);
+    Ok(buf)
+}
+
+// Set the label of the device.
+fn set_label(file: &mut File, label: &str) -> io::Result<()> {
+    let mut bytes = label.as_bytes().to_vec();
+    let mut arg = ChardevBuffer::new(&mut bytes);
+    ioctl(file, CHARDEV_IOC_SET_LABEL, &mut arg)?;
+    Ok(())
+}
+
+// Only accept messages which start with `prefix`, or any message if it's empty.
+fn set_prefix(file: &mut File, prefix: &[u8]) -> io::Result<()> {
+    let mut bytes = prefix.to_vec();
+    let mut arg = ChardevBuffer::new(&mut bytes);
+    ioctl(file, CHARDEV_IOC_SET_PREFIX, &mut arg)?;
+    Ok(())
+}
+
+// Copy every message enqueued on this device to device `target`, or stop copying with `None`.
+fn set_tee(file: &mut File, target: Option<u32>) -> io::Result<()> {
+    let mut value = target.unwrap_or(CHARDEV_TEE_OFF);
+    ioctl(file, CHARDEV_IOC_SET_TEE, &mut value)?;
+    Ok(())
+}
+
+// Append `suffix` to every queued message, returning how many were changed.
+fn append_all(file: &mut File, suffix: &[u8]) -> io::Result<u32> {
+    let mut bytes = suffix.to_vec();
+    let mut arg = ChardevBuffer::new(&mut bytes);
+    Ok(ioctl(file, CHARDEV_IOC_APPEND_ALL, &mut arg)? as u32)
+}
+
+// Get the ABI version and the ceilings of the limits the module was built with.
+fn info(file: &mut File) -> io::Result<ChardevInfo> {
+    let mut info = ChardevInfo::default();
+    ioctl(file, CHARDEV_IOC_INFO, &mut info)?;
+    Ok(info)
+}
+
+// Get the label of the device.
+fn get_label(file: &mut File) -> io::Result<String> {
+    let mut buf = vec![0; CHARDEV_LABEL_MAX];
+    let mut arg = ChardevBuffer::new(&mut buf);
+    let bytes = ioctl(file, CHARDEV_IOC_GET_LABEL, &mut arg)? as usize;
+    Ok(String::from_utf8(buf[..bytes].to_vec()).unwrap())
+}
+
+// Write a message only if the queue is empty, failing with EEXIST otherwise.
+fn write_if_empty(file: &mut File, bytes: &[u8]) -> io::Result<usize> {
+    let mut bytes = bytes.to_vec();
+    let mut arg = ChardevBuffer::new(&mut bytes);
+    Ok(ioctl(file, CHARDEV_IOC_WRITE_IF_EMPTY, &mut arg)? as usize)
+}
+
+// Write a message before the oldest, so it's read next in FIFO mode.
+fn write_front(file: &mut File, bytes: &[u8]) -> io::Result<usize> {
+    let mut bytes = bytes.to_vec();
+    let mut arg = ChardevBuffer::new(&mut bytes);
+    Ok(ioctl(file, CHARDEV_IOC_WRITE_FRONT, &mut arg)? as usize)
+}
+
+// Remove the message which would be read next only if it equals `expected`, failing with EAGAIN otherwise.
+fn pop_if_eq(file: &mut File, expected: &[u8]) -> io::Result<()> {
+    let mut bytes = expected.to_vec();
+    let mut arg = ChardevBuffer::new(&mut bytes);
+    ioctl(file, CHARDEV_IOC_POP_IF_EQ, &mut arg)?;
+    Ok(())
+}
+
+// Make the next write fail with `errno`, which must be EBUSY, EINVAL or ENOMEM.
+fn inject_error(file: &mut File, errno: i32) -> io::Result<()> {
+    let mut value = match errno {
+        libc::EBUSY => CHARDEV_INJECT_EBUSY,
+        libc::EINVAL => CHARDEV_INJECT_EINVAL,
+        libc::ENOMEM => CHARDEV_INJECT_ENOMEM,
+        _ => panic!("can't inject errno {errno}"),
+    };
+    ioctl(file, CHARDEV_IOC_INJECT, &mut value)?;
+    Ok(())
+}
+
+fn set_frozen(file: &mut File, on: bool) -> io::Result<()> {
+    let mut value = on as u32;
+    ioctl(file, CHARDEV_IOC_FREEZE, &mut value)?;
+    Ok(())
+}
+
+fn set_writes_blocked(file: &mut File, on: bool) -> io::Result<()> {
+    let mut value = on as u32;
+    ioctl(file, CHARDEV_IOC_BLOCK_WRITES, &mut value)?;
+    Ok(())
+}
+
+fn set_strict(file: &mut File, on: bool) -> io::Result<()> {
+    let mut value = on as u32;
+    ioctl(file, CHARDEV_IOC_SET_STRICT, &mut value)?;
+    Ok(())
+}
+
+// Set what writes to a full queue fail with, either EBUSY or ENOSPC.
+fn set_full_errno(file: &mut File, code: i32) -> io::Result<()> {
+    let mut value = code as u32;
+    ioctl(file, CHARDEV_IOC_SET_FULL_ERRNO, &mut value)?;
+    Ok(())
+}
+
+// Set whether empty writes enqueue an empty message instead of doing nothing.
+fn set_allow_empty(file: &mut File, on: bool) -> io::Result<()> {
+    let mut value = on as u32;
+    ioctl(file, CHARDEV_IOC_SET_ALLOW_EMPTY, &mut value)?;
+    Ok(())
+}
+
+// Set whether the queue is flushed when its last open file is closed.
+fn set_drain_on_close(file: &mut File, on: bool) -> io::Result<()> {
+    let mut value = on as u32;
+    ioctl(file, CHARDEV_IOC_SET_DRAIN_ON_CLOSE, &mut value)?;
+    Ok(())
+}
+
+fn set_dedup(file: &mut File, on: bool) -> io::Result<()> {
+    let mut value = on as u32;
+    ioctl(file, CHARDEV_IOC_SET_DEDUP, &mut value)?;
+    Ok(())
+}
+
+fn set_timestamp(file: &mut File, enabled: bool) -> io::Result<()> {
+    let mut value = enabled as u32;
+    ioctl(file, CHARDEV_IOC_SET_TIMESTAMP, &mut value)?;
+    Ok(())
+}
+
+// Read a message, waiting at most `ms` milliseconds for one.
+fn read_timeout(file: &mut File, ms: u32) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; max_string_length(file)? as usize];
+    let mut arg = ChardevTimeoutBuffer {
+        data: buf.as_mut_ptr() as u64,
+        length: buf.len() as u64,
+        timeout_ms: ms,
+    };
+    let bytes = ioctl(file, CHARDEV_IOC_READ_TIMEOUT, &mut arg)? as usize;
+    buf.truncate(bytes);
+    Ok(buf)
+}
+
+// Read a message and the time in nanoseconds it was enqueued.
+fn read_with_ts(file: &mut File) -> io::Result<(Vec<u8>, u64)> {
+    let mut buf = vec![0; max_string_length(file)? as usize];
+    let mut arg = ChardevTsBuffer {
+        data: buf.as_mut_ptr() as u64,
+        length: buf.len() as u64,
+        timestamp: 0,
+    };
+    let bytes = ioctl(file, CHARDEV_IOC_READ_TS, &mut arg)? as usize;
+    buf.truncate(bytes);
+    Ok((buf, arg.timestamp))
+}
+
+// Read a message and the sequence number it was enqueued with.
+fn read_with_seq(file: &mut File) -> io::Result<(Vec<u8>, u64)> {
+    let mut buf = vec![0; max_string_length(file)? as usize];
+    let mut arg = ChardevSeqBuffer {
+        data: buf.as_mut_ptr() as u64,
+        length: buf.len() as u64,
+        sequence: 0,
+    };
+    let bytes = ioctl(file, CHARDEV_IOC_READ_SEQ, &mut arg)? as usize;
+    buf.truncate(bytes);
+    Ok((buf, arg.sequence))
+}
+
+// Read a message after its little endian `u32` length, checking the length matches the message.
+fn read_framed(file: &mut File) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; 4 + max_string_length(file)? as usize];
+    let mut arg = ChardevBuffer::new(&mut buf);
+    let bytes = ioctl(file, CHARDEV_IOC_READ_FRAMED, &mut arg)? as usize;
+    buf.truncate(bytes);
+    let length = u32::from_le_bytes(buf[..4].try_into().unwrap()) as usize;
+    assert_eq!(length, bytes - 4);
+    Ok(buf)
+}
+
+// Remove every message into a buffer of the given size, returning the raw length prefixed records.
+fn drain_into(file: &mut File, size: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; size];
+    let mut arg = ChardevBuffer::new(&mut buf);
+    let bytes = ioctl(file, CHARDEV_IOC_DRAIN, &mut arg)? as usize;
+    buf.truncate(bytes);
+    Ok(buf)
+}
+
+// Split the length prefixed records from `CHARDEV_IOC_DRAIN` or `CHARDEV_IOC_SNAPSHOT` into messages.
+fn parse_records(records: &[u8]) -> Vec<Vec<u8>> {
+    let mut messages = Vec::new();
+    let mut rest = records;
+    while !rest.is_empty() {
+        let (length, tail) = rest.split_at(4);
+        let length = u32::from_ne_bytes(length.try_into().unwrap()) as usize;
+        let (message, tail) = tail.split_at(length);
+        messages.push(message.to_vec());
+        rest = tail;
+    }
+    messages
+}
+
+// Remove every message in a single call.
+fn drain_all(file: &mut File) -> io::Result<Vec<Vec<u8>>> {
+    // Big enough for a full queue
+    let size = max_messages(file)? as usize * (4 + max_string_length(file)? as usize);
+    Ok(parse_records(&drain_into(file, size)?))
+}
+
+// Remove every message into `buf` in a single call, failing with EMSGSIZE without removing any if it's too small.
+fn try_drain(file: &mut File, buf: &mut [u8]) -> io::Result<Vec<Vec<u8>>> {
+    let mut arg = ChardevBuffer::new(buf);
+    let bytes = ioctl(file, CHARDEV_IOC_DRAIN, &mut arg)? as usize;
+    Ok(parse_records(&buf[..bytes]))
+}
+
+// Replace every queued message with `messages` in a single call, returning how many were loaded.
+fn load_template(file: &mut File, messages: &[&[u8]]) -> io::Result<u32> {
+    let mut records = Vec::new();
+    for message in messages {
+        records.extend_from_slice(&(message.len() as u32).to_ne_bytes());
+        records.extend_from_slice(message);
     }
+    let mut arg = ChardevBuffer::new(&mut records);
+    Ok(ioctl(file, CHARDEV_IOC_LOAD_TEMPLATE, &mut arg)? as u32)
+}
+
+// Copy every message in a single call without removing them.
+fn snapshot(file: &mut File) -> io::Result<Vec<Vec<u8>>> {
+    let size = max_messages(file)? as usize * (4 + max_string_length(file)? as usize);
+    let mut buf = vec![0; size];
+    let mut arg = ChardevBuffer::new(&mut buf);
+    let bytes = ioctl(file, CHARDEV_IOC_SNAPSHOT, &mut arg)? as usize;
+    Ok(parse_records(&buf[..bytes]))
+}
+
+// Write and drain `msg_count` messages of `msg_size` bytes in batches as large as the queue allows, printing
+// the throughput, and return the total bytes drained.
+fn bench_roundtrip(file: &mut File, msg_count: usize, msg_size: usize) -> io::Result<usize> {
+    let message = vec![b'A'; msg_size];
+    let mut drained = 0;
+    let mut remaining = msg_count;
+    let start = Instant::now();
+    while remaining > 0 {
+        let batch = remaining
+            .min(free_slots(file)? as usize)
+            .min(libc::UIO_MAXIOV as usize);
+        assert!(batch > 0, "queue has no free slots");
+        let messages = vec![&message[..]; batch];
+        assert_eq!(write_vectored_messages(file, &messages)?, batch * msg_size);
+        let read = drain_all(file)?;
+        assert_eq!(read.len(), batch);
+        drained += read.iter().map(Vec::len).sum::<usize>();
+        remaining -= batch;
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{msg_count} messages of {msg_size} bytes in {elapsed:?}, {:.0} messages/sec",
+        msg_count as f64 / elapsed.as_secs_f64()
+    );
+    Ok(drained)
+}
+
+// Remove every message in a single call, joined by `delim`.
+fn read_concat(file: &mut File, delim: u8) -> io::Result<Vec<u8>> {
+    let mut value = delim as u32;
+    ioctl(file, CHARDEV_IOC_SET_DELIM, &mut value)?;
+    // Big enough for a full queue
+    let size = max_messages(file)? as usize * (1 + max_string_length(file)? as usize);
+    let mut buf = vec![0; size];
+    let mut arg = ChardevBuffer::new(&mut buf);
+    let bytes = ioctl(file, CHARDEV_IOC_READ_CONCAT, &mut arg)? as usize;
+    buf.truncate(bytes);
+    Ok(buf)
+}
+
+fn set_capacity(file: &mut File, capacity: u32) -> io::Result<()> {
+    let mut value = capacity;
+    ioctl(file, CHARDEV_IOC_SET_CAPACITY, &mut value)?;
+    Ok(())
+}
+
+// Get the most messages ever queued at once.
+fn high_water(file: &mut File) -> io::Result<u32> {
+    let mut value = 0u32;
+    ioctl(file, CHARDEV_IOC_HIGH_WATER, &mut value)?;
+    Ok(value)
+}
+
+fn reset_stats(file: &mut File) -> io::Result<()> {
+    ioctl(file, CHARDEV_IOC_RESET_STATS, ptr::null_mut::<()>())?;
+    Ok(())
+}
+
+// Drop up to `n` of the oldest messages, returning how many were dropped.
+fn drop_oldest(file: &mut File, n: u32) -> io::Result<u32> {
+    let mut value = n;
+    Ok(ioctl(file, CHARDEV_IOC_DROP_OLDEST, &mut value)? as u32)
+}
+
+// Drop up to `n` of the newest messages, returning how many were dropped.
+fn drop_newest(file: &mut File, n: u32) -> io::Result<u32> {
+    let mut value = n;
+    Ok(ioctl(file, CHARDEV_IOC_DROP_NEWEST, &mut value)? as u32)
+}
+
+// Move every message to the end of device `target_minor`, returning how many were moved.
+fn move_to(file: &mut File, target_minor: u32) -> io::Result<u32> {
+    let mut value = target_minor;
+    Ok(ioctl(file, CHARDEV_IOC_MOVE_TO, &mut value)? as u32)
+}
+
+// Get the counters of this open file.
+fn my_stats(file: &mut File) -> io::Result<ChardevMyStats> {
+    let mut stats = ChardevMyStats::default();
+    ioctl(file, CHARDEV_IOC_MY_STATS, &mut stats)?;
+    Ok(stats)
+}
+
+// Move the message which would be read next to the end of device `target_minor`.
+fn handoff(file: &mut File, target_minor: u32) -> io::Result<()> {
+    let mut value = target_minor;
+    ioctl(file, CHARDEV_IOC_HANDOFF, &mut value)?;
+    Ok(())
+}
+
+// Get the number of open files of the device.
+fn open_count(file: &mut File) -> io::Result<u32> {
+    let mut value = 0u32;
+    ioctl(file, CHARDEV_IOC_OPEN_COUNT, &mut value)?;
+    Ok(value)
+}
+
+// Get the length, free slots and limits of the device in a single call.
+fn status(file: &mut File) -> io::Result<Status> {
+    let mut status = Status::default();
+    ioctl(file, CHARDEV_IOC_STATUS, &mut status)?;
+    Ok(status)
+}
+
+// Get how many more messages fit before writes fail with EBUSY.
+fn free_slots(file: &mut File) -> io::Result<u32> {
+    let mut slots = 0u32;
+    ioctl(file, CHARDEV_IOC_FREE_SLOTS, &mut slots)?;
+    Ok(slots)
+}
+
+// Get how many nanoseconds ago the oldest message was enqueued, which requires it to have a timestamp.
+fn head_age_ns(file: &mut File) -> io::Result<u64> {
+    let mut age = 0u64;
+    ioctl(file, CHARDEV_IOC_HEAD_AGE, &mut age)?;
+    Ok(age)
+}
+
+// Get how many times the queue has been cleared by flushing, dropping every message or loading a template.
+fn generation(file: &mut File) -> io::Result<u64> {
+    let mut generation = 0u64;
+    ioctl(file, CHARDEV_IOC_GENERATION, &mut generation)?;
+    Ok(generation)
+}
+
+// Get the total length of the queued messages.
+fn bytes_queued(file: &mut File) -> io::Result<u64> {
+    let mut bytes = 0u64;
+    ioctl(file, CHARDEV_IOC_BYTES_QUEUED, &mut bytes)?;
+    Ok(bytes)
+}
+
+// Set the maximum total length of the queued messages, 0 for no limit.
+fn set_byte_budget(file: &mut File, bytes: u64) -> io::Result<()> {
+    let mut value = bytes;
+    ioctl(file, CHARDEV_IOC_SET_BYTE_BUDGET, &mut value)?;
+    Ok(())
+}
+
+// Read the number of queued messages from sysfs.
+fn sysfs_queue_len() -> u32 {
+    fs::read_to_string(format!("{SYSFS_PATH}/queue_len"))
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+// Flush the queue through sysfs, this requires root.
+fn sysfs_flush(value: &str) {
+    fs::write(format!("{SYSFS_PATH}/flush"), value).unwrap();
+}
+
+// Parse the `key value` lines of the proc stats file.
+fn read_proc_stats() -> HashMap<String, u64> {
+    fs::read_to_string(PROC_STATS_PATH)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let (key, value) = line.split_once(' ').unwrap();
+            (key.to_string(), value.parse().unwrap())
+        })
+        .collect()
+}
+
+// Parse the `chardevN length hex` lines of the debugfs dump into the queued messages of `device`, oldest first.
+fn read_debugfs_dump(device: &str) -> Vec<Vec<u8>> {
+    fs::read_to_string(DEBUGFS_DUMP_PATH)
+        .unwrap()
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            if fields.next().unwrap() != device {
+                return None;
+            }
+            let length: usize = fields.next().unwrap().parse().unwrap();
+            let hex = fields.next().unwrap_or("");
+            let bytes: Vec<u8> = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+                .collect();
+            assert_eq!(bytes.len(), length);
+            Some(bytes)
+        })
+        .collect()
+}
+
+// Poll for the given events, returning the ones which are ready.
+fn poll(file: &File, events: libc::c_short, timeout_ms: i32) -> io::Result<libc::c_short> {
+    let mut fd = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events,
+        revents: 0,
+    };
+    if unsafe { libc::poll(&mut fd, 1, timeout_ms) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd.revents)
+}
+
+// Whether a read wouldn't block, waiting up to the timeout.
+fn poll_readable(file: &File, timeout_ms: i32) -> bool {
+    poll(file, libc::POLLIN, timeout_ms).unwrap() & libc::POLLIN != 0
+}
+
+// Whether a write wouldn't block, waiting up to the timeout.
+fn poll_writable(file: &File, timeout_ms: i32) -> bool {
+    poll(file, libc::POLLOUT, timeout_ms).unwrap() & libc::POLLOUT != 0
+}
+
+// Open the device for read and write, with `O_NONBLOCK` so reads and writes don't block.
+fn open_nonblocking() -> File {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(DEVICE_PATH)
+        .unwrap()
+}
+
+// Open device `index`, when the module was loaded with `num_devices`, for read and write.
+// Reads and writes don't block.
+fn open_n(index: usize) -> File {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(format!("{DEVICE_PATH}{index}"))
+        .unwrap()
+}
+
+// Open the device for read and write, with `O_NONBLOCK`, returning the error if it can't be opened.
+fn open_nonblocking_result() -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(DEVICE_PATH)
+}
+
+// Open the device for read only, with `O_NONBLOCK`.
+fn open_read_only() -> File {
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(DEVICE_PATH)
+        .unwrap()
+}
+
+// Open the device for write only, with `O_NONBLOCK`.
+fn open_write_only() -> File {
+    OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(DEVICE_PATH)
+        .unwrap()
+}
+
+// Open the device for read and write.
+// Reading from an empty queue waits for a message and writing to a full queue waits for space.
+fn open_blocking() -> File {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(DEVICE_PATH)
+        .unwrap()
+}
+
+#[test]
+fn test_device_exists() {
+    assert!(Path::new(DEVICE_PATH).exists());
+}
+
+#[test]
+fn test_write_read_short() {
+    let mut file = open_nonblocking();
+    let line = "Hello, World!";
+    write_line(&mut file, line).unwrap();
+    assert_eq!(read_line(&mut file).unwrap(), line);
+}
+
+#[test]
+fn test_read_line_preserves_following_messages() {
+    let mut file = open_nonblocking();
+    write_line(&mut file, "First").unwrap();
+    write_line(&mut file, "Second").unwrap();
+    assert_eq!(read_line(&mut file).unwrap(), "First");
+    assert_eq!(read_line(&mut file).unwrap(), "Second");
+}
+
+#[test]
+fn test_write_read_short_no_newline() {
+    let mut file = open_nonblocking();
+    let line = "Hello, World!";
+    write_str(&mut file, line).unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), line);
+}
+
+#[test]
+fn test_write_read_short_null_byte() {
+    let mut file = open_nonblocking();
+    let line = "Hello, World!\0";
+    write_str(&mut file, line).unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), line);
+}
+
+#[test]
+fn test_write_read_fifo_no_newline() {
+    let mut file = open_nonblocking();
+    for i in 0..10 {
+        let line = format!("Write {i}");
+        write_str(&mut file, &line).unwrap();
+    }
+    for i in 0..10 {
+        let line = format!("Write {i}");
+        assert_eq!(read_str(&mut file).unwrap(), line);
+    }
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock // Same as EAGAIN
+    );
+}
+
+#[test]
+fn test_read_empty() {
+    let mut file = open_nonblocking();
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock // Same as EAGAIN
+    );
+}
+
+#[test]
+fn test_write_too_long() {
+    let mut file = open_nonblocking();
+    let max_string_length = max_string_length(&mut file).unwrap() as usize;
+    let line = "A".repeat(max_string_length + 1);
+    let result = write_str(&mut file, &line);
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    // Should still be empty
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+
+    let line = "A".repeat(max_string_length);
+    write_str(&mut file, &line).unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), line);
+
+    let line = "A".repeat(max_string_length - 1);
+    write_str(&mut file, &line).unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), line);
+}
+
+#[test]
+fn test_write_too_long_all_null() {
+    let mut file = open_nonblocking();
+    let max_string_length = max_string_length(&mut file).unwrap() as usize;
+    let line = "\0".repeat(max_string_length + 1);
+    let result = write_str(&mut file, &line);
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    // Should still be empty
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+
+    let line = "\0".repeat(max_string_length);
+    write_str(&mut file, &line).unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), line);
+
+    let line = "\0".repeat(max_string_length - 1);
+    write_str(&mut file, &line).unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), line);
+}
+
+#[test]
+fn test_write_too_long_last_null() {
+    let mut file = open_nonblocking();
+    let max_string_length = max_string_length(&mut file).unwrap() as usize;
+    let line = "A".repeat(max_string_length) + "\0";
+    let result = write_str(&mut file, &line);
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    // Should still be empty
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+
+    let line = "A".repeat(max_string_length - 1) + "\0";
+    write_str(&mut file, &line).unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), line);
+
+    let line = "A".repeat(max_string_length - 2) + "\0";
+    write_str(&mut file, &line).unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), line);
+}
+
+#[test]
+fn test_write_too_long_first_null() {
+    let mut file = open_nonblocking();
+    let max_string_length = max_string_length(&mut file).unwrap() as usize;
+    let line = "\0".to_owned() + &"A".repeat(max_string_length);
+    let result = write_str(&mut file, &line);
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    // Should still be empty
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+
+    let line = "\0".to_owned() + &"A".repeat(max_string_length - 1);
+    write_str(&mut file, &line).unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), line);
+
+    let line = "\0".to_owned() + &"A".repeat(max_string_length - 2);
+    write_str(&mut file, &line).unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), line);
+}
+
+#[test]
+fn test_write_too_long_with_null() {
+    let mut file = open_nonblocking();
+    let max_string_length = max_string_length(&mut file).unwrap() as usize;
+    let line = {
+        let filler = "A".repeat(max_string_length / 2 - 1);
+        format!("{filler}\0{filler}A")
+    };
+    let result = write_str(&mut file, &format!("{line}A"));
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    // Should still be empty
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+
+    write_str(&mut file, &line).unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), line);
+}
+
+#[test]
+fn test_write_too_many() {
+    let mut file = open_nonblocking();
+    let max_messages = max_messages(&mut file).unwrap() as usize;
+    let line = "Hello, World!";
+    for _ in 0..max_messages {
+        write_str(&mut file, line).unwrap();
+    }
+    let result = write_str(&mut file, line);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+    assert_eq!(queue_len(&mut file).unwrap() as usize, max_messages);
+
+    for _ in 0..max_messages {
+        assert_eq!(read_str(&mut file).unwrap(), line);
+    }
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_write_too_many_max_length() {
+    let mut file = open_nonblocking();
+    let max_string_length = max_string_length(&mut file).unwrap() as usize;
+    let max_messages = max_messages(&mut file).unwrap() as usize;
+    let line = "A".repeat(max_string_length);
+    for _ in 0..max_messages {
+        write_str(&mut file, &line).unwrap();
+    }
+    let result = write_str(&mut file, &line);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+
+    for _ in 0..max_messages {
+        assert_eq!(read_str(&mut file).unwrap(), line);
+    }
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_write_lots_fifo() {
+    let mut file = open_nonblocking();
+    let max_messages = max_messages(&mut file).unwrap() as usize;
+
+    for _ in 0..5 {
+        for i in 0..(max_messages / 2 - 1) {
+            write_str(&mut file, &i.to_string()).unwrap();
+        }
+        for i in 0..(max_messages / 2 - 1) {
+            assert_eq!(read_str(&mut file).unwrap(), i.to_string());
+        }
+        assert_eq!(
+            read_str(&mut file).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_write_lots_max_length() {
+    let mut file = open_nonblocking();
+    let max_string_length = max_string_length(&mut file).unwrap() as usize;
+    let max_messages = max_messages(&mut file).unwrap() as usize;
+    let line = "A".repeat(max_string_length);
+
+    for _ in 0..5 {
+        for _ in 0..max_messages {
+            write_str(&mut file, &line).unwrap();
+        }
+        let result = write_str(&mut file, &line);
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+
+        for _ in 0..max_messages {
+            assert_eq!(read_str(&mut file).unwrap(), line);
+        }
+        assert_eq!(
+            read_str(&mut file).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    // Queue size = 0
+    for _ in 0..(max_messages / 2) {
+        write_str(&mut file, &line).unwrap();
+    }
+    // Queue size = max_messages / 2
+    for _ in 0..(max_messages / 2 - 1) {
+        assert_eq!(read_str(&mut file).unwrap(), line);
+    }
+    // Queue size = 1
+    for _ in 0..(max_messages / 2) {
+        write_str(&mut file, &line).unwrap();
+    }
+    // Queue size = max_messages / 2 + 1
+    for _ in 0..(max_messages / 2 + 1) {
+        assert_eq!(read_str(&mut file).unwrap(), line);
+    }
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_empty_after_reading() {
+    let mut file = open_nonblocking();
+    write_str(&mut file, "Hello, World!").unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), "Hello, World!");
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_write_read_invalid_utf_8() {
+    let mut file = open_nonblocking();
+    let bytes = vec![0xC0];
+    write_bytes(&mut file, &bytes).unwrap();
+    assert_eq!(read_bytes(&mut file).unwrap(), bytes);
+}
+
+#[test]
+fn test_write_read_bytes_null() {
+    let mut file = open_nonblocking();
+    let bytes = vec![0xC0, 0x00, 0xC1];
+    write_bytes(&mut file, &bytes).unwrap();
+    assert_eq!(read_bytes(&mut file).unwrap(), bytes);
+}
+
+#[test]
+fn test_open_thread() {
+    let mut file = open_nonblocking();
+
+    let handles = (0..10)
+        .map(|thread_number| {
+            thread::spawn(move || {
+                let mut file = open_nonblocking();
+                thread::sleep(Duration::from_millis(thread_number * 100));
+                write_str(
+                    &mut file,
+                    &format!("Hello, World from thread {thread_number}!"),
+                )
+                .unwrap();
+                assert_eq!(
+                    read_str(&mut file).unwrap(),
+                    format!("Hello, World from thread {thread_number}!"),
+                );
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for thread_number in 0..10 {
+        write_str(
+            &mut file,
+            &format!("Hello, World from thread {thread_number}!"),
+        )
+        .unwrap();
+    }
+    let handles = (0..10)
+        .map(|thread_number| {
+            thread::spawn(move || {
+                let mut file = open_nonblocking();
+                thread::sleep(Duration::from_millis(thread_number * 100));
+                assert_eq!(
+                    read_str(&mut file).unwrap(),
+                    format!("Hello, World from thread {thread_number}!"),
+                );
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_threads_spam() {
+    let mut file = open_nonblocking();
+
+    let handles = (0..16)
+        .map(|_| {
+            thread::spawn(|| {
+                let mut file = open_nonblocking();
+                for _ in 0..10 {
+                    for _ in 0..5 {
+                        write_str(&mut file, "Hello, World!").unwrap();
+                    }
+                    for _ in 0..5 {
+                        assert_eq!(read_str(&mut file).unwrap(), "Hello, World!");
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_flush_clears_queue() {
+    let mut file = open_nonblocking();
+    for i in 0..10 {
+        write_str(&mut file, &format!("Write {i}")).unwrap();
+    }
+    flush(&mut file).unwrap();
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+
+    // Flushing an empty queue is fine too
+    flush(&mut file).unwrap();
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_queue_len() {
+    let mut file = open_nonblocking();
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+    for i in 0..10 {
+        write_str(&mut file, &format!("Write {i}")).unwrap();
+    }
+    assert_eq!(queue_len(&mut file).unwrap(), 10);
+
+    assert_eq!(read_str(&mut file).unwrap(), "Write 0");
+    assert_eq!(queue_len(&mut file).unwrap(), 9);
+
+    flush(&mut file).unwrap();
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+}
+
+#[test]
+fn test_peek_does_not_consume() {
+    let mut file = open_nonblocking();
+    assert_eq!(
+        peek_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+
+    write_str(&mut file, "First").unwrap();
+    write_str(&mut file, "Second").unwrap();
+    assert_eq!(peek_str(&mut file).unwrap(), "First");
+    assert_eq!(peek_str(&mut file).unwrap(), "First");
+    assert_eq!(queue_len(&mut file).unwrap(), 2);
+
+    assert_eq!(read_str(&mut file).unwrap(), "First");
+    assert_eq!(queue_len(&mut file).unwrap(), 1);
+    assert_eq!(peek_str(&mut file).unwrap(), "Second");
+    assert_eq!(read_str(&mut file).unwrap(), "Second");
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+    assert_eq!(
+        peek_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_blocking_read_waits_for_write() {
+    let mut file = open_nonblocking();
+
+    let reader = thread::spawn(|| {
+        let mut file = open_blocking();
+        read_str(&mut file)
+    });
+    thread::sleep(Duration::from_millis(200));
+    write_str(&mut file, "Hello, World!").unwrap();
+
+    assert_eq!(reader.join().unwrap().unwrap(), "Hello, World!");
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_blocking_write_waits_for_space() {
+    let mut file = open_nonblocking();
+    let max_messages = max_messages(&mut file).unwrap() as usize;
+    for i in 0..max_messages {
+        write_str(&mut file, &i.to_string()).unwrap();
+    }
+    let result = write_str(&mut file, "Blocked");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+
+    let writer = thread::spawn(|| {
+        let mut file = open_blocking();
+        write_str(&mut file, "Blocked")
+    });
+    thread::sleep(Duration::from_millis(200));
+    assert!(!writer.is_finished());
+
+    assert_eq!(read_str(&mut file).unwrap(), "0");
+    writer.join().unwrap().unwrap();
+    assert_eq!(queue_len(&mut file).unwrap() as usize, max_messages);
+
+    for i in 1..max_messages {
+        assert_eq!(read_str(&mut file).unwrap(), i.to_string());
+    }
+    assert_eq!(read_str(&mut file).unwrap(), "Blocked");
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_poll() {
+    let mut file = open_nonblocking();
+    let max_messages = max_messages(&mut file).unwrap() as usize;
+    assert!(!poll_readable(&file, 0));
+    assert!(poll_writable(&file, 0));
+
+    write_str(&mut file, "Hello, World!").unwrap();
+    assert!(poll_readable(&file, 0));
+    assert!(poll_writable(&file, 0));
+
+    for _ in 1..max_messages {
+        write_str(&mut file, "Hello, World!").unwrap();
+    }
+    assert!(poll_readable(&file, 0));
+    assert!(!poll_writable(&file, 0));
+
+    flush(&mut file).unwrap();
+    assert!(!poll_readable(&file, 0));
+    assert!(poll_writable(&file, 0));
+}
+
+#[test]
+fn test_poll_wakes_on_write() {
+    let mut file = open_nonblocking();
+
+    let writer = thread::spawn(|| {
+        let mut file = open_nonblocking();
+        thread::sleep(Duration::from_millis(200));
+        write_str(&mut file, "Hello, World!").unwrap();
+    });
+    assert!(poll_readable(&file, 5000));
+    writer.join().unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), "Hello, World!");
+}
+
+#[test]
+fn test_short_buffer_preserves_message() {
+    let mut file = open_nonblocking();
+    let bytes = (0..100).collect::<Vec<u8>>();
+    write_bytes(&mut file, &bytes).unwrap();
+
+    let mut buf = [0; 10];
+    let result = read_into(&mut file, &mut buf);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(90)); // EMSGSIZE, unstable API
+    assert_eq!(queue_len(&mut file).unwrap(), 1);
+
+    let mut buf = [0; 100];
+    assert_eq!(read_into(&mut file, &mut buf).unwrap(), 100);
+    assert_eq!(buf.to_vec(), bytes);
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_overwrite_evicts_oldest() {
+    let mut file = open_nonblocking();
+    let max_messages = max_messages(&mut file).unwrap() as usize;
+    set_overwrite(&mut file, true).unwrap();
+    for i in 0..(max_messages + 5) {
+        write_str(&mut file, &i.to_string()).unwrap();
+    }
+    assert_eq!(queue_len(&mut file).unwrap() as usize, max_messages);
+    assert!(poll_writable(&file, 0));
+    set_overwrite(&mut file, false).unwrap();
+
+    for i in 5..(max_messages + 5) {
+        assert_eq!(read_str(&mut file).unwrap(), i.to_string());
+    }
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_overwrite_disabled_rejects() {
+    let mut file = open_nonblocking();
+    let max_messages = max_messages(&mut file).unwrap() as usize;
+    set_overwrite(&mut file, false).unwrap();
+    for _ in 0..max_messages {
+        write_str(&mut file, "Hello, World!").unwrap();
+    }
+    let result = write_str(&mut file, "Hello, World!");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+    flush(&mut file).unwrap();
+}
+
+#[test]
+fn test_devices_are_isolated() {
+    // Requires the module to be loaded with `num_devices=2` or more
+    let mut first = open_n(0);
+    let mut second = open_n(1);
+    write_str(&mut first, "First device").unwrap();
+    write_str(&mut second, "Second device").unwrap();
+    assert_eq!(queue_len(&mut first).unwrap(), 1);
+    assert_eq!(queue_len(&mut second).unwrap(), 1);
+
+    assert_eq!(read_str(&mut second).unwrap(), "Second device");
+    assert_eq!(
+        read_str(&mut second).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+    assert_eq!(read_str(&mut first).unwrap(), "First device");
+    assert_eq!(
+        read_str(&mut first).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_sysfs_queue_len() {
+    let mut file = open_nonblocking();
+    assert_eq!(sysfs_queue_len(), 0);
+    for i in 0..3 {
+        write_str(&mut file, &format!("Write {i}")).unwrap();
+    }
+    assert_eq!(sysfs_queue_len(), 3);
+
+    assert_eq!(read_str(&mut file).unwrap(), "Write 0");
+    assert_eq!(sysfs_queue_len(), 2);
+
+    flush(&mut file).unwrap();
+    assert_eq!(sysfs_queue_len(), 0);
+}
+
+#[test]
+fn test_sysfs_flush() {
+    let mut file = open_nonblocking();
+    for i in 0..3 {
+        write_str(&mut file, &format!("Write {i}")).unwrap();
+    }
+
+    // Writing 0 does nothing
+    sysfs_flush("0");
+    assert_eq!(queue_len(&mut file).unwrap(), 3);
+
+    sysfs_flush("1");
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_proc_stats() {
+    let mut file = open_nonblocking();
+    let max_messages = max_messages(&mut file).unwrap() as usize;
+    let max_string_length = max_string_length(&mut file).unwrap() as usize;
+    let before = read_proc_stats();
+    let delta = |key: &str| read_proc_stats()[key] - before[key];
+
+    write_str(&mut file, "Test").unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), "Test");
+    assert_eq!(delta("chardev0.messages_written"), 1);
+    assert_eq!(delta("chardev0.messages_read"), 1);
+
+    let s = "a".repeat(max_string_length + 1);
+    assert_eq!(
+        write_str(&mut file, &s).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    assert_eq!(delta("chardev0.rejected_too_long"), 1);
+
+    for i in 0..max_messages {
+        write_str(&mut file, &format!("Write {i}")).unwrap();
+    }
+    let result = write_str(&mut file, "Test");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+    assert_eq!(delta("chardev0.rejected_busy"), 1);
+    assert_eq!(delta("chardev0.messages_written"), 1 + max_messages as u64);
+
+    flush(&mut file).unwrap();
+}
+
+#[test]
+fn test_lifo_ordering() {
+    let mut file = open_nonblocking();
+    set_mode(&mut file, CHARDEV_MODE_LIFO).unwrap();
+    for s in ["a", "b", "c"] {
+        write_str(&mut file, s).unwrap();
+    }
+    assert_eq!(peek_str(&mut file).unwrap(), "c");
+    for s in ["c", "b", "a"] {
+        assert_eq!(read_str(&mut file).unwrap(), s);
+    }
+
+    // Unknown modes are rejected
+    assert_eq!(
+        set_mode(&mut file, 3).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+
+    set_mode(&mut file, CHARDEV_MODE_FIFO).unwrap();
+    for s in ["a", "b", "c"] {
+        write_str(&mut file, s).unwrap();
+    }
+    for s in ["a", "b", "c"] {
+        assert_eq!(read_str(&mut file).unwrap(), s);
+    }
+}
+
+#[test]
+fn test_priority_ordering() {
+    let mut file = open_nonblocking();
+    set_mode(&mut file, CHARDEV_MODE_PRIORITY).unwrap();
+    write_prio(&mut file, b"low 0", 1).unwrap();
+    write_prio(&mut file, b"high 0", 5).unwrap();
+    write_str(&mut file, "default").unwrap();
+    write_prio(&mut file, b"low 1", 1).unwrap();
+    assert_eq!(write_prio(&mut file, b"high 1", 5).unwrap(), 6);
+
+    for s in ["high 0", "high 1", "low 0", "low 1", "default"] {
+        assert_eq!(read_str(&mut file).unwrap(), s);
+    }
+
+    set_mode(&mut file, CHARDEV_MODE_FIFO).unwrap();
+}
+
+#[test]
+fn test_timestamps() {
+    let mut file = open_nonblocking();
+
+    // Messages enqueued with timestamps off have no timestamp
+    write_str(&mut file, "Untimed").unwrap();
+    assert_eq!(read_with_ts(&mut file).unwrap(), (b"Untimed".to_vec(), 0));
+
+    set_timestamp(&mut file, true).unwrap();
+    write_str(&mut file, "First").unwrap();
+    thread::sleep(Duration::from_millis(100));
+    write_str(&mut file, "Second").unwrap();
+    let (first, first_ts) = read_with_ts(&mut file).unwrap();
+    let (second, second_ts) = read_with_ts(&mut file).unwrap();
+    assert_eq!(first, b"First");
+    assert_eq!(second, b"Second");
+    let interval = Duration::from_nanos(second_ts - first_ts);
+    assert!(interval >= Duration::from_millis(100), "{interval:?}");
+    assert!(interval < Duration::from_millis(500), "{interval:?}");
+
+    // Plain reads are unchanged
+    write_str(&mut file, "Test").unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), "Test");
+
+    set_timestamp(&mut file, false).unwrap();
+}
+
+#[test]
+fn test_bytes_queued() {
+    let mut file = open_nonblocking();
+    assert_eq!(bytes_queued(&mut file).unwrap(), 0);
+
+    let messages: [&[u8]; 3] = [b"Hello", b"a\0b\0c", b"\0"];
+    for message in messages {
+        write_bytes(&mut file, message).unwrap();
+    }
+    assert_eq!(bytes_queued(&mut file).unwrap(), 5 + 5 + 1);
+
+    read_bytes(&mut file).unwrap();
+    assert_eq!(bytes_queued(&mut file).unwrap(), 5 + 1);
+
+    flush(&mut file).unwrap();
+    assert_eq!(bytes_queued(&mut file).unwrap(), 0);
+}
+
+#[test]
+fn test_free_slots() {
+    let mut file = open_nonblocking();
+    let max_messages = max_messages(&mut file).unwrap();
+    write_str(&mut file, "Test").unwrap();
+
+    let slots = free_slots(&mut file).unwrap();
+    assert_eq!(slots, max_messages - 1);
+    for i in 0..slots {
+        write_str(&mut file, &format!("Write {i}")).unwrap();
+    }
+    assert_eq!(free_slots(&mut file).unwrap(), 0);
+    let result = write_str(&mut file, "Test");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+
+    flush(&mut file).unwrap();
+    assert_eq!(free_slots(&mut file).unwrap(), max_messages);
+}
+
+#[test]
+fn test_drain_all() {
+    let mut file = open_nonblocking();
+    assert!(drain_all(&mut file).unwrap().is_empty());
+
+    let messages: Vec<Vec<u8>> = (0..10).map(|i| format!("Write {i}").into_bytes()).collect();
+    for message in &messages {
+        write_bytes(&mut file, message).unwrap();
+    }
+
+    // Too small a buffer doesn't consume anything
+    let result = drain_into(&mut file, 4 * 10);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(90)); // EMSGSIZE, unstable API
+    assert_eq!(queue_len(&mut file).unwrap(), 10);
+
+    assert_eq!(drain_all(&mut file).unwrap(), messages);
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_one_write_one_message() {
+    let mut file = open_nonblocking();
+    let payload: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+    write_bytes(&mut file, &payload).unwrap();
+    assert_eq!(queue_len(&mut file).unwrap(), 1);
+    assert_eq!(read_bytes(&mut file).unwrap(), payload);
+}
+
+#[test]
+fn test_zero_length_write() {
+    let mut file = open_nonblocking();
+    // `write_all` skips empty buffers, so call `write` directly to make the syscall
+    assert_eq!(file.write(&[]).unwrap(), 0);
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+
+    // It also succeeds when the queue is full
+    let max_messages = max_messages(&mut file).unwrap();
+    for i in 0..max_messages {
+        write_str(&mut file, &format!("Write {i}")).unwrap();
+    }
+    assert_eq!(file.write(&[]).unwrap(), 0);
+    assert_eq!(queue_len(&mut file).unwrap(), max_messages);
+
+    flush(&mut file).unwrap();
+}
+
+#[test]
+fn test_set_capacity_grow() {
+    let mut file = open_nonblocking();
+    let original = max_messages(&mut file).unwrap();
+    let capacity = original + 10;
+    set_capacity(&mut file, capacity).unwrap();
+    assert_eq!(max_messages(&mut file).unwrap(), capacity);
+
+    for i in 0..capacity {
+        write_str(&mut file, &format!("Write {i}")).unwrap();
+    }
+    let result = write_str(&mut file, "Test");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+
+    flush(&mut file).unwrap();
+    set_capacity(&mut file, original).unwrap();
+}
+
+#[test]
+fn test_set_capacity_shrink() {
+    let mut file = open_nonblocking();
+    let original = max_messages(&mut file).unwrap();
+    set_capacity(&mut file, 2).unwrap();
+    assert_eq!(free_slots(&mut file).unwrap(), 2);
+
+    write_str(&mut file, "Write 0").unwrap();
+    write_str(&mut file, "Write 1").unwrap();
+    let result = write_str(&mut file, "Test");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+
+    // Out of range capacities are rejected
+    assert_eq!(
+        set_capacity(&mut file, 0).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+
+    flush(&mut file).unwrap();
+    set_capacity(&mut file, original).unwrap();
+}
+
+#[test]
+fn test_set_capacity_shrink_occupied() {
+    let mut file = open_nonblocking();
+    let original = max_messages(&mut file).unwrap();
+    for i in 0..3 {
+        write_str(&mut file, &format!("Write {i}")).unwrap();
+    }
+
+    let result = set_capacity(&mut file, 2);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+    assert_eq!(max_messages(&mut file).unwrap(), original);
+    assert_eq!(queue_len(&mut file).unwrap(), 3);
+
+    // Shrinking to exactly the occupancy is fine
+    set_capacity(&mut file, 3).unwrap();
+    for i in 0..3 {
+        assert_eq!(read_str(&mut file).unwrap(), format!("Write {i}"));
+    }
+
+    set_capacity(&mut file, original).unwrap();
+}
+
+#[test]
+fn test_writer_ordering_under_contention() {
+    let mut file = open_nonblocking();
+    let threads = 8;
+    let per_thread = max_messages(&mut file).unwrap() as usize / threads;
+    let sequence = Arc::new((Mutex::new(()), AtomicU64::new(0)));
+
+    let handles = (0..threads)
+        .map(|_| {
+            let sequence = Arc::clone(&sequence);
+            thread::spawn(move || {
+                let mut file = open_nonblocking();
+                for _ in 0..per_thread {
+                    // Holding the mutex across the write means sequence numbers are written in order
+                    let _guard = sequence.0.lock().unwrap();
+                    let n = sequence.1.fetch_add(1, Ordering::SeqCst);
+                    write_str(&mut file, &n.to_string()).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let mut previous = None;
+    for _ in 0..threads * per_thread {
+        let n: u64 = read_str(&mut file).unwrap().parse().unwrap();
+        assert!(previous < Some(n), "{n} read after {previous:?}");
+        previous = Some(n);
+    }
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_seek_reports_queue_len() {
+    let mut file = open_nonblocking();
+    assert_eq!(file.stream_position().unwrap(), 0);
+    write_str(&mut file, "Write 0").unwrap();
+    write_str(&mut file, "Write 1").unwrap();
+    // `stream_position` is `seek(SeekFrom::Current(0))`
+    assert_eq!(file.stream_position().unwrap(), 2);
+
+    for pos in [SeekFrom::Start(5), SeekFrom::End(0), SeekFrom::Current(1)] {
+        let result = file.seek(pos);
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(29)); // ESPIPE, unstable API
+    }
+
+    flush(&mut file).unwrap();
+}
+
+#[test]
+fn test_read_timeout_receives_write() {
+    let mut file = open_nonblocking();
+
+    let writer = thread::spawn(|| {
+        thread::sleep(Duration::from_millis(100));
+        write_str(&mut open_nonblocking(), "Test").unwrap();
+    });
+    assert_eq!(read_timeout(&mut file, 5000).unwrap(), b"Test");
+    writer.join().unwrap();
+}
+
+#[test]
+fn test_read_timeout_expires() {
+    let mut file = open_nonblocking();
+
+    let start = Instant::now();
+    let result = read_timeout(&mut file, 200);
+    let elapsed = start.elapsed();
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(110)); // ETIMEDOUT, unstable API
+    assert!(elapsed >= Duration::from_millis(200), "{elapsed:?}");
+    assert!(elapsed < Duration::from_millis(1000), "{elapsed:?}");
+}
+
+#[test]
+fn test_broadcast_delivery() {
+    let (mut a, mut b) = open_subscribers();
+    set_broadcast(&mut a, true).unwrap();
+
+    write_str(&mut a, "Test").unwrap();
+    assert!(poll_readable(&b, 0));
+    assert_eq!(read_str(&mut a).unwrap(), "Test");
+    assert_eq!(
+        read_str(&mut a).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+    // Kept until every subscriber has read it
+    assert_eq!(queue_len(&mut a).unwrap(), 1);
+    assert_eq!(read_str(&mut b).unwrap(), "Test");
+    assert_eq!(queue_len(&mut a).unwrap(), 0);
+
+    // Closing a subscriber reclaims the messages only it hadn't read
+    write_str(&mut a, "Unread").unwrap();
+    assert_eq!(read_str(&mut a).unwrap(), "Unread");
+    drop(b);
+    assert_eq!(queue_len(&mut a).unwrap(), 0);
+
+    set_broadcast(&mut a, false).unwrap();
+}
+
+#[test]
+fn test_broadcast_write_only_writer() {
+    let mut writer = open_write_only();
+    let mut reader = open_read_only();
+    set_broadcast(&mut reader, true).unwrap();
+
+    // The writer never reads, so it mustn't stop messages being reclaimed once the reader has read them
+    let max_messages = max_messages(&mut reader).unwrap();
+    for i in 0..max_messages + 10 {
+        write_str(&mut writer, &format!("Write {i}")).unwrap();
+        assert_eq!(read_str(&mut reader).unwrap(), format!("Write {i}"));
+        assert_eq!(queue_len(&mut reader).unwrap(), 0);
+    }
+
+    set_broadcast(&mut reader, false).unwrap();
+}
+
+#[test]
+fn test_broadcast_peek_and_drain() {
+    let (mut a, mut b) = open_subscribers();
+    set_broadcast(&mut a, true).unwrap();
+    write_str(&mut a, "First").unwrap();
+    write_str(&mut a, "Second").unwrap();
+    assert_eq!(read_str(&mut a).unwrap(), "First");
+
+    // The queue's head is "First", which `a` has already read, so these can't give either subscriber's next message
+    for result in [
+        peek_str(&mut a).map(drop),
+        peek_len(&mut a).map(drop),
+        peek_at(&mut a, 0).map(drop),
+        snapshot(&mut a).map(drop),
+    ] {
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+    // Nor can these remove "Second", which `b` hasn't read
+    for result in [
+        drain_all(&mut a).map(drop),
+        read_concat(&mut a, b'\n').map(drop),
+    ] {
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+    assert_eq!(queue_len(&mut a).unwrap(), 2);
+    assert_eq!(read_str(&mut b).unwrap(), "First");
+    assert_eq!(read_str(&mut b).unwrap(), "Second");
+    assert_eq!(read_str(&mut a).unwrap(), "Second");
+
+    set_broadcast(&mut a, false).unwrap();
+}
+
+#[test]
+fn test_high_water() {
+    let mut file = open_nonblocking();
+    reset_stats(&mut file).unwrap();
+
+    for i in 0..50 {
+        write_str(&mut file, &format!("Write {i}")).unwrap();
+    }
+    flush(&mut file).unwrap();
+    for i in 0..10 {
+        write_str(&mut file, &format!("Write {i}")).unwrap();
+    }
+    assert_eq!(high_water(&mut file).unwrap(), 50);
+    assert_eq!(read_proc_stats()["chardev0.high_water"], 50);
+
+    flush(&mut file).unwrap();
+}
+
+#[test]
+fn test_reset_stats() {
+    let mut file = open_nonblocking();
+    write_str(&mut file, "Write 0").unwrap();
+    write_str(&mut file, "Write 1").unwrap();
+    read_str(&mut file).unwrap();
+    let too_long = "a".repeat(max_string_length(&mut file).unwrap() as usize + 1);
+    write_str(&mut file, &too_long).unwrap_err();
+
+    reset_stats(&mut file).unwrap();
+    for (key, value) in read_proc_stats() {
+        if key.starts_with("chardev0.") {
+            assert_eq!(value, 0, "{key}");
+        }
+    }
+    assert_eq!(high_water(&mut file).unwrap(), 0);
+
+    // The queue is untouched
+    assert_eq!(read_str(&mut file).unwrap(), "Write 1");
+}
+
+#[test]
+fn test_stream_mode_partial_reads() {
+    let mut file = open_nonblocking();
+    set_stream_mode(&mut file, true).unwrap();
+    let message: Vec<u8> = (0..100).collect();
+    write_bytes(&mut file, &message).unwrap();
+    write_str(&mut file, "Next").unwrap();
+
+    let mut reassembled: Vec<u8> = Vec::new();
+    let mut buf = [0; 30];
+    while reassembled.len() < message.len() {
+        assert_eq!(queue_len(&mut file).unwrap(), 2);
+        let bytes = read_into(&mut file, &mut buf).unwrap();
+        reassembled.extend(&buf[..bytes]);
+    }
+    assert_eq!(reassembled, message);
+    assert_eq!(queue_len(&mut file).unwrap(), 1);
+    // The message is only counted as read once, with its last part
+    let stats = my_stats(&mut file).unwrap();
+    assert_eq!(stats.messages_read, 1);
+    assert_eq!(stats.bytes_read, 100);
+    assert_eq!(read_str(&mut file).unwrap(), "Next");
+
+    // Other files still get whole messages
+    write_bytes(&mut file, &message).unwrap();
+    let result = read_into(&mut open_nonblocking(), &mut buf);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(90)); // EMSGSIZE, unstable API
+
+    flush(&mut file).unwrap();
+}
+
+#[test]
+fn test_stream_mode_interleaved_readers() {
+    let mut a = open_nonblocking();
+    let mut b = open_nonblocking();
+    set_stream_mode(&mut a, true).unwrap();
+    set_stream_mode(&mut b, true).unwrap();
+    let message: Vec<u8> = (0..100).collect();
+    write_bytes(&mut a, &message).unwrap();
+
+    // Each part read is gone from the message, so whichever file reads next continues from there
+    let mut buf = [0; 30];
+    assert_eq!(read_into(&mut a, &mut buf).unwrap(), 30);
+    assert_eq!(buf[..], message[..30]);
+    assert_eq!(read_into(&mut b, &mut buf).unwrap(), 30);
+    assert_eq!(buf[..], message[30..60]);
+    assert_eq!(peek_len(&mut a).unwrap(), 40);
+    assert_eq!(bytes_queued(&mut a).unwrap(), 40);
+    assert_eq!(read_into(&mut a, &mut buf).unwrap(), 30);
+    assert_eq!(buf[..], message[60..90]);
+
+    // A file not in stream mode reads the rest as a whole message
+    let mut c = open_nonblocking();
+    assert_eq!(read_bytes(&mut c).unwrap(), message[90..]);
+    assert_eq!(queue_len(&mut c).unwrap(), 0);
+}
+
+#[test]
+fn test_writev_messages() {
+    let mut file = open_nonblocking();
+    let messages: [&[u8]; 3] = [b"First", b"Second", b"Third"];
+    assert_eq!(write_vectored_messages(&mut file, &messages).unwrap(), 16);
+    assert_eq!(queue_len(&mut file).unwrap(), 3);
+    for message in messages {
+        assert_eq!(read_bytes(&mut file).unwrap(), message);
+    }
+}
+
+#[test]
+fn test_writev_is_atomic() {
+    let mut file = open_nonblocking();
+    let max_messages = max_messages(&mut file).unwrap();
+    for i in 0..max_messages - 2 {
+        write_str(&mut file, &format!("Write {i}")).unwrap();
+    }
+
+    // Three don't fit in the two free slots, so none are queued
+    let result = write_vectored_messages(&mut file, &[b"a", b"b", b"c"]);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+    assert_eq!(queue_len(&mut file).unwrap(), max_messages - 2);
+
+    // A message which is too long fails the whole call
+    let too_long = vec![b'a'; max_string_length(&mut file).unwrap() as usize + 1];
+    let result = write_vectored_messages(&mut file, &[b"a", &too_long]);
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(queue_len(&mut file).unwrap(), max_messages - 2);
+
+    flush(&mut file).unwrap();
+}
+
+#[test]
+fn test_readv_messages() {
+    let mut file = open_nonblocking();
+    write_str(&mut file, "First").unwrap();
+    write_str(&mut file, "Second").unwrap();
+
+    let (mut a, mut b, mut c) = ([0; 16], [0; 16], [0; 16]);
+    let count = read_vectored_messages(&mut file, &mut [&mut a, &mut b, &mut c]).unwrap();
+    assert_eq!(count, 2);
+    assert_eq!(&a[..5], b"First");
+    assert_eq!(&b[..6], b"Second");
+    assert_eq!(c, [0; 16]);
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+}
+
+#[test]
+fn test_readv_short_buffer() {
+    let mut file = open_nonblocking();
+    write_str(&mut file, "Short").unwrap();
+    write_str(&mut file, "Much longer").unwrap();
+
+    // The second message doesn't fit so is left queued
+    let (mut a, mut b) = ([0; 8], [0; 8]);
+    let count = read_vectored_messages(&mut file, &mut [&mut a, &mut b]).unwrap();
+    assert_eq!(count, 1);
+    assert_eq!(&a[..5], b"Short");
+
+    let result = read_vectored_messages(&mut file, &mut [&mut a]);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(90)); // EMSGSIZE, unstable API
+    assert_eq!(read_str(&mut file).unwrap(), "Much longer");
+}
+
+#[test]
+fn test_sendfile_rejected() {
+    let mut file = open_nonblocking();
+    let path = std::env::temp_dir().join("chardev_sendfile");
+    fs::write(&path, "Test").unwrap();
+    let source = File::open(&path).unwrap();
+
+    // `sendfile` gives the driver kernel pages rather than user space buffers
+    let result =
+        unsafe { libc::sendfile(file.as_raw_fd(), source.as_raw_fd(), ptr::null_mut(), 4) };
+    let error = io::Error::last_os_error();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(result, -1);
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+}
+
+// Run by `scripts/reload_test.sh`, which unloads the module after this leaves the queue full.
+#[test]
+#[ignore]
+fn test_load_unload_with_residual() {
+    let mut file = open_nonblocking();
+    let max_messages = max_messages(&mut file).unwrap();
+    for i in queue_len(&mut file).unwrap()..max_messages {
+        write_str(&mut file, &format!("Write {i}")).unwrap();
+    }
+    assert_eq!(queue_len(&mut file).unwrap(), max_messages);
+}
+
+// Run by `scripts/reload_test.sh` after reloading the module.
+#[test]
+#[ignore]
+fn test_empty_after_reload() {
+    let mut file = open_nonblocking();
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+    assert_eq!(read_proc_stats()["chardev0.messages_written"], 0);
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_open_nonblocking_does_not_block() {
+    let mut file = open_nonblocking();
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_open_blocking_blocks() {
+    let (sender, receiver) = mpsc::channel();
+    let reader = thread::spawn(move || {
+        let result = read_str(&mut open_blocking());
+        sender.send(()).unwrap();
+        result
+    });
+
+    // Nothing to read, so the reader is still waiting
+    assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+
+    write_str(&mut open_nonblocking(), "Test").unwrap();
+    receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(reader.join().unwrap().unwrap(), "Test");
+}
+
+#[test]
+fn test_rate_limit() {
+    let mut file = open_nonblocking();
+    set_rate(&mut file, 10).unwrap();
+
+    let mut written = 0;
+    let mut throttled = 0;
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(200) {
+        match write_str(&mut file, "Test") {
+            Ok(()) => written += 1,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => throttled += 1,
+            Err(err) => panic!("{err}"),
+        }
+    }
+    // One immediately, then one every 100ms
+    assert!((1..=4).contains(&written), "{written}");
+    assert!(throttled > 0);
+    assert_eq!(queue_len(&mut file).unwrap(), written);
+
+    // Other files aren't limited
+    let mut other = open_nonblocking();
+    write_str(&mut other, "Test").unwrap();
+    write_str(&mut other, "Test").unwrap();
+
+    set_rate(&mut file, 0).unwrap();
+    write_str(&mut file, "Test").unwrap();
+    write_str(&mut file, "Test").unwrap();
+    flush(&mut file).unwrap();
+}
+
+#[test]
+fn test_rate_limit_failed_writes() {
+    let mut file = open_nonblocking();
+    set_rate(&mut file, 10).unwrap();
+
+    // Writes which fail don't use up the rate limit
+    set_strict(&mut file, true).unwrap();
+    let result = write_str(&mut file, "   ");
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    set_strict(&mut file, false).unwrap();
+    write_str(&mut file, "Test").unwrap();
+    let result = write_str(&mut file, "Test");
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+    thread::sleep(Duration::from_millis(150));
+    set_writes_blocked(&mut file, true).unwrap();
+    let result = write_str(&mut file, "Test");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+    set_writes_blocked(&mut file, false).unwrap();
+    write_str(&mut file, "Test").unwrap();
+
+    set_rate(&mut file, 0).unwrap();
+    flush(&mut file).unwrap();
+}
+
+#[test]
+fn test_drop_oldest() {
+    let mut file = open_nonblocking();
+    for i in 0..10 {
+        write_str(&mut file, &format!("Write {i}")).unwrap();
+    }
+
+    assert_eq!(drop_oldest(&mut file, 3).unwrap(), 3);
+    assert_eq!(queue_len(&mut file).unwrap(), 7);
+    assert_eq!(read_str(&mut file).unwrap(), "Write 3");
+
+    // Only as many as are queued are dropped
+    assert_eq!(drop_oldest(&mut file, 100).unwrap(), 6);
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+}
+
+#[test]
+fn test_drop_newest() {
+    let mut file = open_nonblocking();
+    for s in ["a", "b", "c", "d", "e"] {
+        write_str(&mut file, s).unwrap();
+    }
+
+    assert_eq!(drop_newest(&mut file, 2).unwrap(), 2);
+    for s in ["a", "b", "c"] {
+        assert_eq!(read_str(&mut file).unwrap(), s);
+    }
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+    assert_eq!(drop_newest(&mut file, 1).unwrap(), 0);
+}
+
+#[test]
+fn test_dedup() {
+    let mut file = open_nonblocking();
+    set_dedup(&mut file, true).unwrap();
+    for s in ["x", "x", "y", "y", "x"] {
+        write_str(&mut file, s).unwrap();
+    }
+    assert_eq!(queue_len(&mut file).unwrap(), 3);
+    for s in ["x", "y", "x"] {
+        assert_eq!(read_str(&mut file).unwrap(), s);
+    }
+
+    set_dedup(&mut file, false).unwrap();
+    write_str(&mut file, "x").unwrap();
+    write_str(&mut file, "x").unwrap();
+    assert_eq!(queue_len(&mut file).unwrap(), 2);
+    flush(&mut file).unwrap();
+}
+
+#[test]
+fn test_move_to() {
+    // Requires the module to be loaded with `num_devices=2` or more
+    let mut staging = open_n(1);
+    let mut live = open_n(0);
+    write_str(&mut live, "Live").unwrap();
+    for i in 0..3 {
+        write_str(&mut staging, &format!("Staged {i}")).unwrap();
+    }
+
+    assert_eq!(move_to(&mut staging, 0).unwrap(), 3);
+    assert_eq!(queue_len(&mut staging).unwrap(), 0);
+    assert_eq!(queue_len(&mut live).unwrap(), 4);
+    assert_eq!(read_str(&mut live).unwrap(), "Live");
+    for i in 0..3 {
+        assert_eq!(read_str(&mut live).unwrap(), format!("Staged {i}"));
+    }
+
+    assert_eq!(
+        move_to(&mut live, 0).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+}
+
+#[test]
+fn test_poll_hangup() {
+    let mut reader = open_read_only();
+    let mut writer = open_write_only();
+    write_str(&mut writer, "Test").unwrap();
+    drop(writer);
+
+    // There's still a message to read
+    assert_eq!(poll(&reader, libc::POLLIN, 0).unwrap() & libc::POLLHUP, 0);
+    assert_eq!(read_str(&mut reader).unwrap(), "Test");
+    assert_ne!(poll(&reader, libc::POLLIN, 0).unwrap() & libc::POLLHUP, 0);
+
+    // A new writer clears it
+    let _writer = open_write_only();
+    assert_eq!(poll(&reader, libc::POLLIN, 0).unwrap() & libc::POLLHUP, 0);
+}
+
+#[test]
+fn test_open_count() {
+    let mut file = open_nonblocking();
+    let baseline = open_count(&mut file).unwrap();
+    {
+        let _first = open_nonblocking();
+        {
+            let _second = open_read_only();
+            assert_eq!(open_count(&mut file).unwrap(), baseline + 2);
+        }
+        assert_eq!(open_count(&mut file).unwrap(), baseline + 1);
+    }
+    assert_eq!(open_count(&mut file).unwrap(), baseline);
+}
+
+#[test]
+fn test_strict_rejects_blank() {
+    let mut file = open_nonblocking();
+    set_strict(&mut file, true).unwrap();
+    assert_eq!(
+        write_str(&mut file, "   \t\n").unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    assert_eq!(
+        write_str(&mut file, "\r\n").unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+
+    // Only all whitespace is rejected, null bytes aren't whitespace
+    write_str(&mut file, " x ").unwrap();
+    write_bytes(&mut file, b" \0 ").unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), " x ");
+    assert_eq!(read_bytes(&mut file).unwrap(), b" \0 ");
+
+    set_strict(&mut file, false).unwrap();
+    write_str(&mut file, "   ").unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), "   ");
+}
+
+#[test]
+fn test_read_seq() {
+    let mut file = open_nonblocking();
+    write_str(&mut file, "a").unwrap();
+    write_str(&mut file, "b").unwrap();
+    write_str(&mut file, "c").unwrap();
+
+    let (a, first) = read_with_seq(&mut file).unwrap();
+    let (b, second) = read_with_seq(&mut file).unwrap();
+    let (c, third) = read_with_seq(&mut file).unwrap();
+    assert_eq!((a, b, c), (b"a".to_vec(), b"b".to_vec(), b"c".to_vec()));
+    assert_eq!(second, first + 1);
+    assert_eq!(third, second + 1);
+    assert_eq!(
+        read_with_seq(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_fcntl_nonblocking() {
+    let mut file = open_blocking();
+    set_nonblocking(&mut file, true).unwrap();
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+
+    set_nonblocking(&mut file, false).unwrap();
+    let writer = thread::spawn(|| {
+        let mut file = open_nonblocking();
+        thread::sleep(Duration::from_millis(200));
+        write_str(&mut file, "Hello, World!").unwrap();
+    });
+    assert_eq!(read_str(&mut file).unwrap(), "Hello, World!");
+    writer.join().unwrap();
+}
+
+#[test]
+fn test_labels() {
+    let mut staging = open_n(0);
+    let mut live = open_n(1);
+    assert_eq!(get_label(&mut staging).unwrap(), "");
+
+    set_label(&mut staging, "staging").unwrap();
+    set_label(&mut live, "live").unwrap();
+    assert_eq!(get_label(&mut staging).unwrap(), "staging");
+    assert_eq!(get_label(&mut live).unwrap(), "live");
+    // The label belongs to the device, not the open file
+    assert_eq!(get_label(&mut open_n(0)).unwrap(), "staging");
+
+    assert_eq!(
+        set_label(&mut staging, &"a".repeat(CHARDEV_LABEL_MAX + 1))
+            .unwrap_err()
+            .kind(),
+        io::ErrorKind::InvalidInput
+    );
+    assert_eq!(get_label(&mut staging).unwrap(), "staging");
+
+    set_label(&mut staging, "").unwrap();
+    set_label(&mut live, "").unwrap();
+}
+
+#[test]
+fn test_trailing_nulls_near_max() {
+    let mut file = open_nonblocking();
+    let max_string_length = max_string_length(&mut file).unwrap() as usize;
+    let line = "A".repeat(max_string_length - 5) + "\0\0\0\0\0";
+    write_str(&mut file, &line).unwrap();
+    assert_eq!(read_bytes(&mut file).unwrap(), line.as_bytes());
+
+    // A message of only nulls is kept at its full length too
+    let line = "\0".repeat(max_string_length);
+    write_str(&mut file, &line).unwrap();
+    assert_eq!(read_bytes(&mut file).unwrap(), line.as_bytes());
+}
+
+#[test]
+fn test_throughput_smoke() {
+    let mut file = open_nonblocking();
+    assert_eq!(bench_roundtrip(&mut file, 500, 64).unwrap(), 500 * 64);
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+}
+
+#[test]
+fn test_large_queue_perf() {
+    let mut file = open_nonblocking();
+    let max_messages = max_messages(&mut file).unwrap();
+
+    // Enqueueing and dequeueing are O(1), so even a full queue is quick to fill and drain
+    let start = Instant::now();
+    for i in 0..max_messages {
+        write_str(&mut file, &format!("Message {i}")).unwrap();
+    }
+    for i in 0..max_messages {
+        assert_eq!(read_str(&mut file).unwrap(), format!("Message {i}"));
+    }
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_blocking_read_interrupted() {
+    ignore_signal_without_restart(libc::SIGUSR1);
+
+    let (sender, receiver) = mpsc::channel();
+    let reader = thread::spawn(move || {
+        let mut file = open_blocking();
+        sender.send(unsafe { libc::pthread_self() }).unwrap();
+        read_str(&mut file)
+    });
+    let thread = receiver.recv().unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    let start = Instant::now();
+    assert_eq!(unsafe { libc::pthread_kill(thread, libc::SIGUSR1) }, 0);
+    assert_eq!(
+        reader.join().unwrap().unwrap_err().kind(),
+        io::ErrorKind::Interrupted
+    );
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn test_peek_len() {
+    let mut file = open_nonblocking();
+    assert_eq!(
+        peek_len(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+
+    let bytes = vec![b'A'; 1234];
+    write_bytes(&mut file, &bytes).unwrap();
+    assert_eq!(peek_len(&mut file).unwrap(), 1234);
+    assert_eq!(peek_len(&mut file).unwrap(), 1234);
+    assert_eq!(queue_len(&mut file).unwrap(), 1);
+
+    let mut buf = vec![0; peek_len(&mut file).unwrap() as usize];
+    assert_eq!(read_into(&mut file, &mut buf).unwrap(), 1234);
+    assert_eq!(buf, bytes);
+}
+
+#[test]
+fn test_direction_enforced() {
+    let mut reader = open_read_only();
+    let mut writer = open_write_only();
+    let mut both = open_nonblocking();
+
+    let result = read_str(&mut writer);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EBADF));
+    let result = write_str(&mut reader, "Hello, World!");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EBADF));
+    // The ioctl variants check the direction too
+    let result = write_prio(&mut reader, b"Hello, World!", 1);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EBADF));
+    let result = read_timeout(&mut writer, 0);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EBADF));
+    assert_eq!(queue_len(&mut both).unwrap(), 0);
+    // Including the ones which remove every message
+    write_str(&mut both, "Hello, World!").unwrap();
+    let result = drain_all(&mut writer);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EBADF));
+    let result = read_concat(&mut writer, b'\n');
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EBADF));
+    assert_eq!(queue_len(&mut both).unwrap(), 1);
+    assert_eq!(read_str(&mut both).unwrap(), "Hello, World!");
+
+    write_str(&mut both, "Hello, World!").unwrap();
+    assert_eq!(read_str(&mut both).unwrap(), "Hello, World!");
+}
+
+#[test]
+fn test_debugfs_dump() {
+    let mut file = open_nonblocking();
+    let bytes = vec![0xC0, 0x00, 0xFF, b'\n', 0xC1];
+    write_bytes(&mut file, &bytes).unwrap();
+    write_str(&mut file, "Hello, World!").unwrap();
+
+    assert_eq!(
+        read_debugfs_dump("chardev0"),
+        vec![bytes.clone(), b"Hello, World!".to_vec()]
+    );
+    // Dumping doesn't remove anything
+    assert_eq!(queue_len(&mut file).unwrap(), 2);
+    assert_eq!(read_bytes(&mut file).unwrap(), bytes);
+    assert_eq!(read_str(&mut file).unwrap(), "Hello, World!");
+    assert!(read_debugfs_dump("chardev0").is_empty());
+}
+
+#[test]
+fn test_inject_error() {
+    let mut file = open_nonblocking();
+    for errno in [libc::EBUSY, libc::EINVAL, libc::ENOMEM] {
+        inject_error(&mut file, errno).unwrap();
+        let result = write_str(&mut file, "Hello, World!");
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(errno));
+        assert_eq!(queue_len(&mut file).unwrap(), 0);
+
+        // Only the next write fails
+        write_str(&mut file, "Hello, World!").unwrap();
+        assert_eq!(read_str(&mut file).unwrap(), "Hello, World!");
+    }
+}
+
+#[test]
+fn test_sigio_on_message() {
+    let mut file = open_nonblocking();
+    enable_async(&mut file).unwrap();
+    SIGIO_RECEIVED.store(false, Ordering::SeqCst);
+
+    thread::spawn(|| {
+        let mut file = open_nonblocking();
+        write_str(&mut file, "Hello, World!").unwrap();
+    })
+    .join()
+    .unwrap();
+
+    let start = Instant::now();
+    while !SIGIO_RECEIVED.load(Ordering::SeqCst) {
+        assert!(start.elapsed() < Duration::from_secs(1), "no SIGIO");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(read_str(&mut file).unwrap(), "Hello, World!");
+}
+
+#[test]
+fn test_write_if_empty() {
+    let mut file = open_nonblocking();
+    assert_eq!(write_if_empty(&mut file, b"token").unwrap(), 5);
+    let result = write_if_empty(&mut file, b"token");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EEXIST));
+    assert_eq!(queue_len(&mut file).unwrap(), 1);
+
+    assert_eq!(read_str(&mut file).unwrap(), "token");
+    assert_eq!(write_if_empty(&mut file, b"token").unwrap(), 5);
+    assert_eq!(read_str(&mut file).unwrap(), "token");
+}
+
+#[test]
+fn test_no_torn_reads() {
+    const THREADS: usize = 8;
+    const MESSAGES: usize = 200;
+
+    // Each message is its own header repeated, so a splice of two messages can't pass as a valid one
+    fn pattern(thread: usize, index: usize) -> Vec<u8> {
+        format!("T{thread:02}I{index:04}").repeat(8).into_bytes()
+    }
+
+    let read = Arc::new(Mutex::new(Vec::new()));
+    let writers = (0..THREADS)
+        .map(|thread| {
+            thread::spawn(move || {
+                let mut file = open_nonblocking();
+                for index in 0..MESSAGES {
+                    let message = pattern(thread, index);
+                    while let Err(error) = write_bytes(&mut file, &message) {
+                        assert_eq!(error.raw_os_error(), Some(16)); // EBUSY, unstable API
+                        thread::yield_now();
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    let readers = (0..THREADS)
+        .map(|_| {
+            let read = Arc::clone(&read);
+            thread::spawn(move || {
+                let mut file = open_nonblocking();
+                while read.lock().unwrap().len() < THREADS * MESSAGES {
+                    match read_bytes(&mut file) {
+                        Ok(message) => read.lock().unwrap().push(message),
+                        Err(error) => {
+                            assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+                            thread::yield_now();
+                        }
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in writers.into_iter().chain(readers) {
+        handle.join().unwrap();
+    }
+
+    let mut read = Arc::try_unwrap(read).unwrap().into_inner().unwrap();
+    for message in &read {
+        assert_eq!(message.len(), 64);
+        assert_eq!(*message, message[..8].repeat(8), "torn read");
+    }
+    read.sort();
+    let mut expected = (0..THREADS)
+        .flat_map(|thread| (0..MESSAGES).map(move |index| pattern(thread, index)))
+        .collect::<Vec<_>>();
+    expected.sort();
+    assert_eq!(read, expected);
+}
+
+#[test]
+fn test_byte_budget() {
+    let mut file = open_nonblocking();
+    set_byte_budget(&mut file, 10 * 1024).unwrap();
+
+    let message = vec![b'A'; 1000];
+    let mut written = 0;
+    let error = loop {
+        match write_bytes(&mut file, &message) {
+            Ok(()) => written += 1,
+            Err(error) => break error,
+        }
+        assert!(bytes_queued(&mut file).unwrap() <= 10 * 1024);
+    };
+    assert_eq!(error.raw_os_error(), Some(16)); // EBUSY, unstable API
+    assert_eq!(written, 10);
+    assert!(free_slots(&mut file).unwrap() > 0);
+    // A smaller message still fits in what's left of the budget
+    write_bytes(&mut file, &message[..240]).unwrap();
+    assert_eq!(bytes_queued(&mut file).unwrap(), 10 * 1024);
+
+    // The budget can't be lowered below what's queued
+    let result = set_byte_budget(&mut file, 1024);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+    set_byte_budget(&mut file, 0).unwrap();
+    write_bytes(&mut file, &message).unwrap();
+    flush(&mut file).unwrap();
+}
+
+#[test]
+fn test_byte_budget_wakes_every_writer() {
+    let mut file = open_nonblocking();
+    set_byte_budget(&mut file, 1000).unwrap();
+    for _ in 0..10 {
+        write_bytes(&mut file, &[b'A'; 100]).unwrap();
+    }
+
+    // The long writer waits first, so would take the only wakeup if writers were woken one at a time
+    let long_writer = thread::spawn(|| write_bytes(&mut open_blocking(), &[b'L'; 500]));
+    thread::sleep(Duration::from_millis(200));
+    let (sender, receiver) = mpsc::channel();
+    let short_writer = thread::spawn(move || {
+        let result = write_bytes(&mut open_blocking(), &[b'S'; 100]);
+        sender.send(()).unwrap();
+        result
+    });
+    assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+
+    // Reading one message makes room for the short message but not the long one
+    assert_eq!(read_bytes(&mut file).unwrap(), [b'A'; 100]);
+    receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    short_writer.join().unwrap().unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert!(!long_writer.is_finished());
+
+    flush(&mut file).unwrap();
+    long_writer.join().unwrap().unwrap();
+    assert_eq!(read_bytes(&mut file).unwrap(), [b'L'; 500]);
+    set_byte_budget(&mut file, 0).unwrap();
+}
+
+#[test]
+fn test_writev_middle_too_long() {
+    let mut file = open_nonblocking();
+    let max_string_length = max_string_length(&mut file).unwrap() as usize;
+
+    let too_long = vec![b'b'; max_string_length + 1];
+    let result = write_vectored_messages(&mut file, &[b"a", &too_long, b"c"]);
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+
+    // Only each message is limited, not their total
+    let longest = vec![b'b'; max_string_length];
+    let messages: [&[u8]; 3] = [&longest, &longest, &longest];
+    assert_eq!(
+        write_vectored_messages(&mut file, &messages).unwrap(),
+        3 * max_string_length
+    );
+    for message in messages {
+        assert_eq!(read_bytes(&mut file).unwrap(), message);
+    }
+}
+
+#[test]
+fn test_read_concat() {
+    let mut file = open_nonblocking();
+    write_str(&mut file, "a").unwrap();
+    write_str(&mut file, "b").unwrap();
+    write_str(&mut file, "c").unwrap();
+    assert_eq!(read_concat(&mut file, b'|').unwrap(), b"a|b|c");
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+    assert_eq!(read_concat(&mut file, b'|').unwrap(), b"");
+
+    // Exactly enough room is enough, one byte less leaves every message queued
+    write_str(&mut file, "ab").unwrap();
+    write_str(&mut file, "cd").unwrap();
+    let mut buf = [0; 4];
+    let mut arg = ChardevBuffer::new(&mut buf);
+    let result = ioctl(&file, CHARDEV_IOC_READ_CONCAT, &mut arg);
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(90)); // EMSGSIZE, unstable API
+    assert_eq!(queue_len(&mut file).unwrap(), 2);
+    let mut buf = [0; 5];
+    let mut arg = ChardevBuffer::new(&mut buf);
+    assert_eq!(ioctl(&file, CHARDEV_IOC_READ_CONCAT, &mut arg).unwrap(), 5);
+    assert_eq!(&buf, b"ab|cd");
+
+    // The largest possible length doesn't overflow into no room at all
+    write_str(&mut file, "ab").unwrap();
+    write_str(&mut file, "cd").unwrap();
+    let mut buf = [0; 5];
+    let mut arg = ChardevBuffer::new(&mut buf);
+    arg.length = u64::MAX;
+    assert_eq!(ioctl(&file, CHARDEV_IOC_READ_CONCAT, &mut arg).unwrap(), 5);
+    assert_eq!(&buf, b"ab|cd");
+}
+
+#[test]
+fn test_seq_is_64_bit() {
+    // The sequence number is a full `__u64` in the ioctl struct, after the two `__u64` buffer fields
+    assert_eq!(std::mem::size_of::<ChardevSeqBuffer>(), 24);
+    assert_eq!(std::mem::offset_of!(ChardevSeqBuffer, sequence), 16);
+
+    let mut file = open_nonblocking();
+    reset_stats(&mut file).unwrap();
+    for i in 0..100 {
+        write_str(&mut file, &format!("Message {i}")).unwrap();
+    }
+    for i in 0..100u64 {
+        let (message, sequence): (Vec<u8>, u64) = read_with_seq(&mut file).unwrap();
+        assert_eq!(message, format!("Message {i}").into_bytes());
+        assert_eq!(sequence, i);
+    }
+}
+
+#[test]
+fn test_freeze() {
+    let mut file = open_nonblocking();
+    write_str(&mut file, "Hello, World!").unwrap();
+    set_frozen(&mut file, true).unwrap();
+    assert_eq!(
+        read_str(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+    // Writes are still accepted
+    write_str(&mut file, "Second").unwrap();
+    assert_eq!(queue_len(&mut file).unwrap(), 2);
+    // Draining doesn't remove anything either
+    assert_eq!(
+        drain_all(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+    assert_eq!(queue_len(&mut file).unwrap(), 2);
+
+    // A blocked reader gets the message once unfrozen
+    let reader = thread::spawn(|| read_str(&mut open_blocking()).unwrap());
+    thread::sleep(Duration::from_millis(200));
+    assert!(!reader.is_finished());
+    set_frozen(&mut file, false).unwrap();
+    assert_eq!(reader.join().unwrap(), "Hello, World!");
+    assert_eq!(read_str(&mut file).unwrap(), "Second");
+}
+
+#[test]
+fn test_block_writes() {
+    let mut file = open_nonblocking();
+    write_str(&mut file, "First").unwrap();
+    write_str(&mut file, "Second").unwrap();
+    set_writes_blocked(&mut file, true).unwrap();
+    let result = write_str(&mut file, "Third");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+
+    // Reads still drain the queue
+    assert_eq!(read_str(&mut file).unwrap(), "First");
+    assert_eq!(read_str(&mut file).unwrap(), "Second");
+    let result = write_str(&mut file, "Third");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+
+    set_writes_blocked(&mut file, false).unwrap();
+    write_str(&mut file, "Third").unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), "Third");
+}
+
+#[test]
+fn test_mmap_stats() {
+    let mut file = open_nonblocking();
+    let stats = StatsMapping::new(&file).unwrap();
+    let before = stats.read();
+    assert_eq!(before.queue_len, 0);
+
+    write_str(&mut file, "a").unwrap();
+    write_str(&mut file, "bc").unwrap();
+    write_str(&mut file, "def").unwrap();
+    let after = stats.read();
+    assert_eq!(after.queue_len, 3);
+    assert_eq!(after.bytes_queued, 6);
+    assert_eq!(after.messages_written, before.messages_written + 3);
+    assert!(after.high_water >= 3);
+
+    assert_eq!(read_str(&mut file).unwrap(), "a");
+    let after = stats.read();
+    assert_eq!(after.queue_len, 2);
+    assert_eq!(after.bytes_queued, 5);
+    assert_eq!(after.messages_read, before.messages_read + 1);
+    flush(&mut file).unwrap();
+    assert_eq!(stats.read().queue_len, 0);
+
+    // The page can't be mapped writable
+    let address = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            std::mem::size_of::<ChardevStatsPage>(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    assert_eq!(address, libc::MAP_FAILED);
+}
+
+#[test]
+fn test_prefix_filter() {
+    let mut file = open_nonblocking();
+    set_prefix(&mut file, b"cmd:").unwrap();
+    write_str(&mut file, "cmd:go").unwrap();
+    assert_eq!(
+        write_str(&mut file, "go").unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    // Shorter than the prefix
+    assert_eq!(
+        write_str(&mut file, "cmd").unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    assert_eq!(queue_len(&mut file).unwrap(), 1);
+    assert_eq!(read_str(&mut file).unwrap(), "cmd:go");
+
+    assert_eq!(
+        set_prefix(&mut file, &[b'a'; 17]).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    set_prefix(&mut file, b"").unwrap();
+    write_str(&mut file, "go").unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), "go");
+}
+
+#[test]
+fn test_too_long_on_full_queue() {
+    let mut file = open_nonblocking();
+    let max_messages = max_messages(&mut file).unwrap();
+    let max_string_length = max_string_length(&mut file).unwrap() as usize;
+    for i in 0..max_messages {
+        write_str(&mut file, &format!("Message {i}")).unwrap();
+    }
+
+    // The length is checked before the capacity
+    let result = write_str(&mut file, &"A".repeat(max_string_length + 1));
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    let result = write_str(&mut file, "Hello, World!");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+
+    let messages = drain_all(&mut file).unwrap();
+    assert_eq!(messages.len(), max_messages as usize);
+    for (i, message) in messages.into_iter().enumerate() {
+        assert_eq!(message, format!("Message {i}").into_bytes());
+    }
+}
+
+#[test]
+fn test_tee() {
+    // Requires the module to be loaded with `num_devices=2` or more
+    let mut primary = open_n(0);
+    let mut tap = open_n(1);
+    assert_eq!(
+        set_tee(&mut primary, Some(0)).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    set_tee(&mut primary, Some(1)).unwrap();
+    for i in 0..3 {
+        write_str(&mut primary, &format!("Message {i}")).unwrap();
+    }
+    set_tee(&mut primary, None).unwrap();
+    write_str(&mut primary, "Not copied").unwrap();
+
+    for i in 0..3 {
+        assert_eq!(read_str(&mut primary).unwrap(), format!("Message {i}"));
+        assert_eq!(read_str(&mut tap).unwrap(), format!("Message {i}"));
+    }
+    assert_eq!(read_str(&mut primary).unwrap(), "Not copied");
+    assert_eq!(queue_len(&mut tap).unwrap(), 0);
+
+    // A full tap doesn't affect the primary
+    let max_messages = max_messages(&mut tap).unwrap();
+    for i in 0..max_messages {
+        write_str(&mut tap, &format!("Tap {i}")).unwrap();
+    }
+    set_tee(&mut primary, Some(1)).unwrap();
+    write_str(&mut primary, "Dropped by the tap").unwrap();
+    set_tee(&mut primary, None).unwrap();
+    assert_eq!(read_str(&mut primary).unwrap(), "Dropped by the tap");
+    assert_eq!(queue_len(&mut tap).unwrap(), max_messages);
+    flush(&mut tap).unwrap();
+
+    // Copies the tap deduplicates don't count as written to it
+    set_dedup(&mut tap, true).unwrap();
+    let before = read_proc_stats()["chardev1.messages_written"];
+    set_tee(&mut primary, Some(1)).unwrap();
+    write_str(&mut primary, "Same").unwrap();
+    write_str(&mut primary, "Same").unwrap();
+    set_tee(&mut primary, None).unwrap();
+    set_dedup(&mut tap, false).unwrap();
+    assert_eq!(queue_len(&mut tap).unwrap(), 1);
+    assert_eq!(read_proc_stats()["chardev1.messages_written"] - before, 1);
+    flush(&mut primary).unwrap();
+    flush(&mut tap).unwrap();
+}
+
+#[test]
+fn test_peek_at() {
+    let mut file = open_nonblocking();
+    for message in ["a", "b", "c"] {
+        write_str(&mut file, message).unwrap();
+    }
+
+    assert_eq!(peek_at(&mut file, 1).unwrap(), "b");
+    assert_eq!(peek_at(&mut file, 0).unwrap(), "a");
+    assert_eq!(peek_at(&mut file, 2).unwrap(), "c");
+    assert_eq!(
+        peek_at(&mut file, 3).unwrap_err().raw_os_error(),
+        Some(libc::ERANGE)
+    );
+    assert_eq!(queue_len(&mut file).unwrap(), 3);
+    for message in ["a", "b", "c"] {
+        assert_eq!(read_str(&mut file).unwrap(), message);
+    }
+}
+
+#[test]
+fn test_rotate() {
+    let mut file = open_nonblocking();
+    assert_eq!(
+        rotate(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+    for message in ["a", "b", "c"] {
+        write_str(&mut file, message).unwrap();
+    }
+
+    for message in ["a", "b", "c"] {
+        assert_eq!(rotate(&mut file).unwrap(), message);
+    }
+    assert_eq!(queue_len(&mut file).unwrap(), 3);
+    for message in ["a", "b", "c"] {
+        assert_eq!(read_str(&mut file).unwrap(), message);
+    }
+
+    // Rotating in broadcast mode would deliver the message again to files which already read it
+    set_broadcast(&mut file, true).unwrap();
+    write_str(&mut file, "a").unwrap();
+    assert_eq!(
+        rotate(&mut file).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    set_broadcast(&mut file, false).unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), "a");
+}
+
+#[test]
+fn test_read_empty_message() {
+    let mut file = open_nonblocking();
+    set_allow_empty(&mut file, true).unwrap();
+    assert_eq!(file.write(&[]).unwrap(), 0);
+    write_str(&mut file, "x").unwrap();
+    assert_eq!(queue_len(&mut file).unwrap(), 2);
+
+    // An empty message is read successfully, unlike an empty queue
+    assert_eq!(read_bytes(&mut file).unwrap(), b"");
+    assert_eq!(queue_len(&mut file).unwrap(), 1);
+    assert_eq!(read_str(&mut file).unwrap(), "x");
+    assert_eq!(
+        read_bytes(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+
+    set_allow_empty(&mut file, false).unwrap();
+    assert_eq!(file.write(&[]).unwrap(), 0);
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+}
+
+#[test]
+fn test_snapshot() {
+    let mut file = open_nonblocking();
+    assert!(snapshot(&mut file).unwrap().is_empty());
+    let messages: Vec<Vec<u8>> = (0..5)
+        .map(|i| format!("Message {i}").into_bytes())
+        .collect();
+    for message in &messages {
+        file.write_all(message).unwrap();
+    }
+
+    let copy = snapshot(&mut file).unwrap();
+    assert_eq!(copy, messages);
+    assert_eq!(queue_len(&mut file).unwrap(), messages.len() as u32);
+    assert_eq!(drain_all(&mut file).unwrap(), copy);
+}
+
+#[test]
+fn test_max_length_leading_null() {
+    let mut file = open_nonblocking();
+    let max_string_length = max_string_length(&mut file).unwrap() as usize;
+    let mut message = vec![b'A'; max_string_length];
+    message[0] = 0;
+    file.write_all(&message).unwrap();
+    let read = read_bytes(&mut file).unwrap();
+    assert_eq!(read.len(), max_string_length);
+    assert_eq!(read, message);
+
+    // One more byte is too long, however many of them are null
+    message.push(0);
+    assert_eq!(
+        file.write_all(&message).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+}
+
+#[test]
+fn test_drain_too_small_consumes_nothing() {
+    let mut file = open_nonblocking();
+    for i in 0..4 {
+        write_str(&mut file, &format!("Message {i}")).unwrap();
+    }
+
+    // Enough for every message but the last
+    let mut buf = vec![0; 3 * (4 + "Message 0".len())];
+    assert_eq!(
+        try_drain(&mut file, &mut buf).unwrap_err().raw_os_error(),
+        Some(90) // EMSGSIZE, unstable API
+    );
+    assert_eq!(queue_len(&mut file).unwrap(), 4);
+
+    let mut buf = vec![0; 4 * (4 + "Message 0".len())];
+    let messages = try_drain(&mut file, &mut buf).unwrap();
+    assert_eq!(messages.len(), 4);
+    for (i, message) in messages.into_iter().enumerate() {
+        assert_eq!(message, format!("Message {i}").into_bytes());
+    }
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+}
+
+#[test]
+fn test_my_stats() {
+    let mut file = open_nonblocking();
+    let mut other = open_nonblocking();
+    assert_eq!(my_stats(&mut file).unwrap(), ChardevMyStats::default());
+    write_str(&mut file, "Hello").unwrap();
+    write_str(&mut file, "World!").unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), "Hello");
+
+    assert_eq!(
+        my_stats(&mut file).unwrap(),
+        ChardevMyStats {
+            bytes_written: 11,
+            bytes_read: 5,
+            messages_written: 2,
+            messages_read: 1,
+        }
+    );
+    // Other open files of the device have their own counters
+    assert_eq!(read_str(&mut other).unwrap(), "World!");
+    assert_eq!(my_stats(&mut file).unwrap().messages_read, 1);
+    assert_eq!(
+        my_stats(&mut other).unwrap(),
+        ChardevMyStats {
+            bytes_read: 6,
+            messages_read: 1,
+            ..Default::default()
+        }
+    );
+
+    // They're per open, so start again when the device is reopened
+    drop(file);
+    let mut file = open_nonblocking();
+    assert_eq!(my_stats(&mut file).unwrap(), ChardevMyStats::default());
+}
+
+#[test]
+fn test_notify_watermark() {
+    let mut file = open_nonblocking();
+    enable_async(&mut file).unwrap();
+    set_notify_watermark(&mut file, 3).unwrap();
+    SIGIO_RECEIVED.store(false, Ordering::SeqCst);
+
+    write_str(&mut file, "First").unwrap();
+    write_str(&mut file, "Second").unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert!(!SIGIO_RECEIVED.load(Ordering::SeqCst));
+
+    write_str(&mut file, "Third").unwrap();
+    let start = Instant::now();
+    while !SIGIO_RECEIVED.load(Ordering::SeqCst) {
+        assert!(start.elapsed() < Duration::from_secs(1), "no SIGIO");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    set_notify_watermark(&mut file, 0).unwrap();
+    flush(&mut file).unwrap();
+}
+
+#[test]
+fn test_cross_handle_visibility() {
+    // Every open file of a device shares its queue
+    let mut first = open_nonblocking();
+    let mut second = open_nonblocking();
+    write_str(&mut first, "shared").unwrap();
+    assert_eq!(read_str(&mut second).unwrap(), "shared");
+    assert_eq!(
+        read_str(&mut first).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_load_template() {
+    let mut file = open_nonblocking();
+    write_str(&mut file, "Replaced").unwrap();
+    let template: [&[u8]; 3] = [b"first", b"", b"third"];
+    assert_eq!(load_template(&mut file, &template).unwrap(), 3);
+
+    assert_eq!(queue_len(&mut file).unwrap(), 3);
+    for message in template {
+        assert_eq!(read_bytes(&mut file).unwrap(), message);
+    }
+    assert_eq!(
+        read_bytes(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+
+    // Too many messages leaves the queue as it was
+    write_str(&mut file, "Kept").unwrap();
+    let max_messages = max_messages(&mut file).unwrap() as usize;
+    let template = vec![&b"x"[..]; max_messages + 1];
+    assert_eq!(
+        load_template(&mut file, &template)
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::EBUSY)
+    );
+    assert_eq!(read_str(&mut file).unwrap(), "Kept");
+}
+
+#[test]
+fn test_head_age() {
+    let mut file = open_nonblocking();
+    assert_eq!(
+        head_age_ns(&mut file).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+    write_str(&mut file, "Untimed").unwrap();
+    assert_eq!(
+        head_age_ns(&mut file).unwrap_err().raw_os_error(),
+        Some(libc::ENODATA)
+    );
+    flush(&mut file).unwrap();
+
+    set_timestamp(&mut file, true).unwrap();
+    write_str(&mut file, "Hello, World!").unwrap();
+    thread::sleep(Duration::from_millis(100));
+    write_str(&mut file, "Newer").unwrap();
+    let age = head_age_ns(&mut file).unwrap();
+    assert!(age >= 100_000_000, "age {age}");
+    assert!(age < 10_000_000_000, "age {age}");
+    set_timestamp(&mut file, false).unwrap();
+    flush(&mut file).unwrap();
+}
+
+#[test]
+fn test_empty_read_is_eagain() {
+    let mut file = open_nonblocking();
+    // The errno itself, rather than whatever maps to `WouldBlock`
+    assert_eq!(read_str(&mut file).unwrap_err().raw_os_error(), Some(11));
+    assert_eq!(libc::EAGAIN, 11);
+}
+
+#[test]
+fn test_handoff() {
+    // Requires the module to be loaded with `num_devices=2` or more
+    let mut stage = open_n(0);
+    let mut next = open_n(1);
+    write_str(&mut stage, "First").unwrap();
+    write_str(&mut stage, "Second").unwrap();
+
+    handoff(&mut stage, 1).unwrap();
+    assert_eq!(queue_len(&mut stage).unwrap(), 1);
+    assert_eq!(queue_len(&mut next).unwrap(), 1);
+    assert_eq!(read_str(&mut next).unwrap(), "First");
+    assert_eq!(
+        read_str(&mut next).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+
+    // A full target leaves both devices unchanged
+    let max_messages = max_messages(&mut next).unwrap();
+    for i in 0..max_messages {
+        write_str(&mut next, &format!("Message {i}")).unwrap();
+    }
+    assert_eq!(
+        handoff(&mut stage, 1).unwrap_err().raw_os_error(),
+        Some(libc::EBUSY)
+    );
+    assert_eq!(queue_len(&mut stage).unwrap(), 1);
+    assert_eq!(queue_len(&mut next).unwrap(), max_messages);
+    flush(&mut next).unwrap();
+
+    assert_eq!(read_str(&mut stage).unwrap(), "Second");
+    assert_eq!(
+        handoff(&mut stage, 1).unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
     assert_eq!(
-        read_str(&mut file).unwrap_err().kind(),
-        io::ErrorKind::WouldBlock // Same as EAGAIN
+        handoff(&mut stage, 0).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
     );
+
+    // The message handed off is the one a read would return, whatever the mode
+    set_mode(&mut stage, CHARDEV_MODE_LIFO).unwrap();
+    write_str(&mut stage, "Older").unwrap();
+    write_str(&mut stage, "Newer").unwrap();
+    handoff(&mut stage, 1).unwrap();
+    set_mode(&mut stage, CHARDEV_MODE_FIFO).unwrap();
+    assert_eq!(read_str(&mut next).unwrap(), "Newer");
+    assert_eq!(read_str(&mut stage).unwrap(), "Older");
 }
 
 #[test]
-fn test_read_empty() {
-    let mut file = open();
-    assert_eq!(
-        read_str(&mut file).unwrap_err().kind(),
-        io::ErrorKind::WouldBlock // Same as EAGAIN
-    );
+fn test_fail_alloc() {
+    // Requires the module to be built with `DEBUG=1`
+    let mut file = open_nonblocking();
+    fail_next_alloc(&mut file).unwrap();
+    let result = write_str(&mut file, "Hello, World!");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::ENOMEM));
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+
+    // Only the next allocation fails
+    write_str(&mut file, "Hello, World!").unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), "Hello, World!");
 }
 
 #[test]
-fn test_write_too_long() {
-    let mut file = open();
-    let line = "A".repeat(MAX_STRING_LENGTH + 1);
-    let result = write_str(&mut file, &line);
-    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
-    // Should still be empty
+fn test_read_framed() {
+    let mut file = open_nonblocking();
+    write_str(&mut file, "Hello").unwrap();
+    let frame = read_framed(&mut file).unwrap();
+    assert_eq!(frame[..4], 5u32.to_le_bytes());
+    assert_eq!(&frame[4..], b"Hello");
     assert_eq!(
-        read_str(&mut file).unwrap_err().kind(),
+        read_framed(&mut file).unwrap_err().kind(),
         io::ErrorKind::WouldBlock
     );
+}
 
-    let line = "A".repeat(MAX_STRING_LENGTH);
-    write_str(&mut file, &line).unwrap();
-    assert_eq!(read_str(&mut file).unwrap(), line);
+#[test]
+fn test_close_with_async() {
+    let mut file = open_nonblocking();
+    enable_async(&mut file).unwrap();
+    drop(file);
 
-    let line = "A".repeat(MAX_STRING_LENGTH - 1);
-    write_str(&mut file, &line).unwrap();
-    assert_eq!(read_str(&mut file).unwrap(), line);
+    // Enqueueing mustn't signal the closed file
+    let mut file = open_nonblocking();
+    write_str(&mut file, "Hello, World!").unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), "Hello, World!");
 }
 
 #[test]
-fn test_write_too_long_all_null() {
-    let mut file = open();
-    let line = "\0".repeat(MAX_STRING_LENGTH + 1);
-    let result = write_str(&mut file, &line);
-    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
-    // Should still be empty
+fn test_status() {
+    let mut file = open_nonblocking();
+    let max_messages = max_messages(&mut file).unwrap();
+    let max_string_length = max_string_length(&mut file).unwrap();
+    let check = |file: &mut File, len: u32, bytes: u64| {
+        let status = status(file).unwrap();
+        assert_eq!(status.len + status.free, status.max_messages, "{status:?}");
+        assert_eq!(status.len, len, "{status:?}");
+        assert_eq!(status.bytes, bytes, "{status:?}");
+        assert_eq!(status.max_messages, max_messages, "{status:?}");
+        assert_eq!(status.max_len, max_string_length, "{status:?}");
+    };
+
+    check(&mut file, 0, 0);
+    write_str(&mut file, "Hello").unwrap();
+    write_str(&mut file, "World!").unwrap();
+    check(&mut file, 2, 11);
+    read_str(&mut file).unwrap();
+    check(&mut file, 1, 6);
+    for _ in 1..max_messages {
+        write_str(&mut file, "x").unwrap();
+    }
+    check(&mut file, max_messages, 6 + max_messages as u64 - 1);
+    flush(&mut file).unwrap();
+    check(&mut file, 0, 0);
+}
+
+#[test]
+fn test_read_typed() {
+    let mut file = open_nonblocking();
+    write_typed(&mut file, b"First", 1).unwrap();
+    write_typed(&mut file, b"Other", 2).unwrap();
+    write_typed(&mut file, b"Second", 1).unwrap();
+
+    assert_eq!(read_typed(&mut file, 1).unwrap(), b"First");
+    assert_eq!(read_typed(&mut file, 1).unwrap(), b"Second");
     assert_eq!(
-        read_str(&mut file).unwrap_err().kind(),
+        read_typed(&mut file, 1).unwrap_err().kind(),
         io::ErrorKind::WouldBlock
     );
+    assert_eq!(queue_len(&mut file).unwrap(), 1);
 
-    let line = "\0".repeat(MAX_STRING_LENGTH);
-    write_str(&mut file, &line).unwrap();
-    assert_eq!(read_str(&mut file).unwrap(), line);
+    // Messages written with `write` have type 0
+    write_str(&mut file, "Untyped").unwrap();
+    assert_eq!(read_typed(&mut file, 0).unwrap(), b"Untyped");
+    assert_eq!(read_str(&mut file).unwrap(), "Other");
+}
 
-    let line = "\0".repeat(MAX_STRING_LENGTH - 1);
-    write_str(&mut file, &line).unwrap();
-    assert_eq!(read_str(&mut file).unwrap(), line);
+#[test]
+fn test_is_empty() {
+    let mut file = open_nonblocking();
+    assert!(is_empty(&mut file).unwrap());
+    write_str(&mut file, "Hello").unwrap();
+    write_str(&mut file, "World!").unwrap();
+    assert!(!is_empty(&mut file).unwrap());
+    assert_eq!(drain_all(&mut file).unwrap().len(), 2);
+    assert!(is_empty(&mut file).unwrap());
 }
 
 #[test]
-fn test_write_too_long_last_null() {
-    let mut file = open();
-    let line = "A".repeat(MAX_STRING_LENGTH) + "\0";
-    let result = write_str(&mut file, &line);
-    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
-    // Should still be empty
+fn test_blocking_write_interrupted() {
+    ignore_signal_without_restart(libc::SIGUSR1);
+    let mut file = open_nonblocking();
+    let max_messages = max_messages(&mut file).unwrap();
+    for i in 0..max_messages {
+        write_str(&mut file, &format!("Message {i}")).unwrap();
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let writer = thread::spawn(move || {
+        let mut file = open_blocking();
+        sender.send(unsafe { libc::pthread_self() }).unwrap();
+        // `write_all` retries when interrupted, so call `write` directly
+        file.write(b"Blocked")
+    });
+    let thread = receiver.recv().unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    let start = Instant::now();
+    assert_eq!(unsafe { libc::pthread_kill(thread, libc::SIGUSR1) }, 0);
+    assert_eq!(
+        writer.join().unwrap().unwrap_err().kind(),
+        io::ErrorKind::Interrupted
+    );
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    assert_eq!(queue_len(&mut file).unwrap(), max_messages);
+    for i in 0..max_messages {
+        assert_eq!(read_str(&mut file).unwrap(), format!("Message {i}"));
+    }
     assert_eq!(
         read_str(&mut file).unwrap_err().kind(),
         io::ErrorKind::WouldBlock
     );
-
-    let line = "A".repeat(MAX_STRING_LENGTH - 1) + "\0";
-    write_str(&mut file, &line).unwrap();
-    assert_eq!(read_str(&mut file).unwrap(), line);
-
-    let line = "A".repeat(MAX_STRING_LENGTH - 2) + "\0";
-    write_str(&mut file, &line).unwrap();
-    assert_eq!(read_str(&mut file).unwrap(), line);
 }
 
 #[test]
-fn test_write_too_long_first_null() {
-    let mut file = open();
-    let line = "\0".to_owned() + &"A".repeat(MAX_STRING_LENGTH);
-    let result = write_str(&mut file, &line);
-    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
-    // Should still be empty
+fn test_size_range() {
+    let mut file = open_nonblocking();
+    assert_eq!(size_range(&mut file).unwrap(), ChardevSizeRange::default());
+    for length in [10, 100, 50] {
+        write_str(&mut file, &"A".repeat(length)).unwrap();
+    }
     assert_eq!(
-        read_str(&mut file).unwrap_err().kind(),
-        io::ErrorKind::WouldBlock
+        size_range(&mut file).unwrap(),
+        ChardevSizeRange { min: 10, max: 100 }
     );
+    flush(&mut file).unwrap();
+}
 
-    let line = "\0".to_owned() + &"A".repeat(MAX_STRING_LENGTH - 1);
-    write_str(&mut file, &line).unwrap();
-    assert_eq!(read_str(&mut file).unwrap(), line);
+#[test]
+fn test_blocking_readv() {
+    let reader = thread::spawn(|| {
+        let mut file = open_blocking();
+        let (mut a, mut b, mut c) = ([0; 16], [0; 16], [0; 16]);
+        let count = read_vectored_messages(&mut file, &mut [&mut a, &mut b, &mut c]).unwrap();
+        (count, a, b)
+    });
+    thread::sleep(Duration::from_millis(200));
 
-    let line = "\0".to_owned() + &"A".repeat(MAX_STRING_LENGTH - 2);
-    write_str(&mut file, &line).unwrap();
-    assert_eq!(read_str(&mut file).unwrap(), line);
+    // A single `writev` enqueues both at once, so the reader can't wake between them
+    let mut file = open_nonblocking();
+    let start = Instant::now();
+    write_vectored_messages(&mut file, &[b"First", b"Second"]).unwrap();
+    let (count, a, b) = reader.join().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(count, 2);
+    assert_eq!(&a[..5], b"First");
+    assert_eq!(&b[..6], b"Second");
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
 }
 
 #[test]
-fn test_write_too_long_with_null() {
-    let mut file = open();
-    let line = {
-        let filler = "A".repeat(MAX_STRING_LENGTH / 2 - 1);
-        format!("{filler}\0{filler}A")
-    };
-    let result = write_str(&mut file, &format!("{line}A"));
-    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
-    // Should still be empty
+fn test_full_errno() {
+    let mut file = open_nonblocking();
     assert_eq!(
-        read_str(&mut file).unwrap_err().kind(),
-        io::ErrorKind::WouldBlock
+        set_full_errno(&mut file, libc::EAGAIN).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
     );
+    set_full_errno(&mut file, libc::ENOSPC).unwrap();
+    let max_messages = max_messages(&mut file).unwrap();
+    for i in 0..max_messages {
+        write_str(&mut file, &format!("Message {i}")).unwrap();
+    }
+    let result = write_str(&mut file, "Hello, World!");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(28)); // ENOSPC
+    flush(&mut file).unwrap();
 
-    write_str(&mut file, &line).unwrap();
-    assert_eq!(read_str(&mut file).unwrap(), line);
+    // Blocked writes still fail with EBUSY
+    set_writes_blocked(&mut file, true).unwrap();
+    let result = write_str(&mut file, "Hello, World!");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+    set_writes_blocked(&mut file, false).unwrap();
+    for i in 0..max_messages {
+        write_str(&mut file, &format!("Message {i}")).unwrap();
+    }
+
+    set_full_errno(&mut file, libc::EBUSY).unwrap();
+    let result = write_str(&mut file, "Hello, World!");
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+    flush(&mut file).unwrap();
 }
 
 #[test]
-fn test_write_too_many() {
-    let mut file = open();
-    let line = "Hello, World!";
-    for _ in 0..MAX_MESSAGES {
-        write_str(&mut file, line).unwrap();
-    }
-    let result = write_str(&mut file, line);
-    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+fn test_generation() {
+    let mut file = open_nonblocking();
+    let start = generation(&mut file).unwrap();
+    write_str(&mut file, "Hello, World!").unwrap();
+    assert_eq!(read_str(&mut file).unwrap(), "Hello, World!");
+    assert_eq!(generation(&mut file).unwrap(), start);
 
-    for _ in 0..MAX_MESSAGES {
-        assert_eq!(read_str(&mut file).unwrap(), line);
+    write_str(&mut file, "Flushed").unwrap();
+    flush(&mut file).unwrap();
+    assert_eq!(generation(&mut file).unwrap(), start + 1);
+
+    // Dropping counts whether or not it empties the queue, but only if it drops something
+    write_str(&mut file, "a").unwrap();
+    write_str(&mut file, "b").unwrap();
+    assert_eq!(drop_oldest(&mut file, 1).unwrap(), 1);
+    assert_eq!(generation(&mut file).unwrap(), start + 2);
+    assert_eq!(drop_newest(&mut file, 5).unwrap(), 1);
+    assert_eq!(generation(&mut file).unwrap(), start + 3);
+    assert_eq!(drop_oldest(&mut file, 1).unwrap(), 0);
+    assert_eq!(drop_newest(&mut file, 1).unwrap(), 0);
+    assert_eq!(generation(&mut file).unwrap(), start + 3);
+
+    // Reading every message at once doesn't lose any, so doesn't count
+    write_str(&mut file, "c").unwrap();
+    assert_eq!(drain_all(&mut file).unwrap(), vec![b"c".to_vec()]);
+    write_str(&mut file, "d").unwrap();
+    assert_eq!(read_concat(&mut file, b'\n').unwrap(), b"d");
+    assert_eq!(generation(&mut file).unwrap(), start + 3);
+}
+
+#[test]
+fn test_read_reverse() {
+    let mut file = open_nonblocking();
+    for message in ["a", "b", "c"] {
+        write_str(&mut file, message).unwrap();
     }
+
+    assert_eq!(read_str(&mut file).unwrap(), "a");
+    assert_eq!(read_reverse(&mut file).unwrap(), b"c");
+    assert_eq!(queue_len(&mut file).unwrap(), 1);
+    assert_eq!(read_str(&mut file).unwrap(), "b");
     assert_eq!(
-        read_str(&mut file).unwrap_err().kind(),
+        read_reverse(&mut file).unwrap_err().kind(),
         io::ErrorKind::WouldBlock
     );
 }
 
 #[test]
-fn test_write_too_many_max_length() {
-    let mut file = open();
-    let line = "A".repeat(MAX_STRING_LENGTH);
-    for _ in 0..MAX_MESSAGES {
-        write_str(&mut file, &line).unwrap();
-    }
-    let result = write_str(&mut file, &line);
-    assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+fn test_append_all() {
+    let mut file = open_nonblocking();
+    write_str(&mut file, "a").unwrap();
+    write_str(&mut file, "b").unwrap();
+    assert_eq!(append_all(&mut file, b"!").unwrap(), 2);
+    assert_eq!(bytes_queued(&mut file).unwrap(), 4);
+    assert_eq!(read_str(&mut file).unwrap(), "a!");
+    assert_eq!(read_str(&mut file).unwrap(), "b!");
 
-    for _ in 0..MAX_MESSAGES {
-        assert_eq!(read_str(&mut file).unwrap(), line);
-    }
+    // If any message would be too long, none change
+    let max_string_length = max_string_length(&mut file).unwrap() as usize;
+    write_str(&mut file, "c").unwrap();
+    write_str(&mut file, &"A".repeat(max_string_length)).unwrap();
     assert_eq!(
-        read_str(&mut file).unwrap_err().kind(),
-        io::ErrorKind::WouldBlock
+        append_all(&mut file, b"!").unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
     );
+    assert_eq!(read_str(&mut file).unwrap(), "c");
+    assert_eq!(read_str(&mut file).unwrap(), "A".repeat(max_string_length));
 }
 
 #[test]
-fn test_write_lots_fifo() {
-    let mut file = open();
-
-    for _ in 0..5 {
-        for i in 0..(MAX_MESSAGES / 2 - 1) {
-            write_str(&mut file, &i.to_string()).unwrap();
-        }
-        for i in 0..(MAX_MESSAGES / 2 - 1) {
-            assert_eq!(read_str(&mut file).unwrap(), i.to_string());
-        }
-        assert_eq!(
-            read_str(&mut file).unwrap_err().kind(),
-            io::ErrorKind::WouldBlock
-        );
-    }
+fn test_read_write_while_loaded() {
+    let mut file = open_nonblocking();
+    write_str(&mut file, "Hello").unwrap();
+    assert_eq!(queue_len(&mut file).unwrap(), 1);
+    assert_eq!(read_str(&mut file).unwrap(), "Hello");
     assert_eq!(
         read_str(&mut file).unwrap_err().kind(),
         io::ErrorKind::WouldBlock
     );
 }
 
+// Run as root by `scripts/unload_test.sh`, as it unloads the module.
+// Unload the module with `rmmod`, returning whether it succeeded.
+fn rmmod() -> bool {
+    std::process::Command::new("rmmod")
+        .arg("charDeviceDriver")
+        .stderr(std::process::Stdio::null())
+        .status()
+        .unwrap()
+        .success()
+}
+
+// Writers keep opening the device, writing and reading while the module is unloaded.
+// Open files keep the module loaded, so `rmmod` fails while one is open and every read or write of an open file reaches
+// the driver. Once the module is unloaded, opening the device fails with ENXIO.
 #[test]
-fn test_write_lots_max_length() {
-    let mut file = open();
-    let line = "A".repeat(MAX_STRING_LENGTH);
+#[ignore]
+fn test_unload_with_writers() {
+    {
+        let _file = open_nonblocking();
+        assert!(!rmmod());
+    }
 
-    for _ in 0..5 {
-        for _ in 0..MAX_MESSAGES {
-            write_str(&mut file, &line).unwrap();
-        }
-        let result = write_str(&mut file, &line);
-        assert_eq!(result.unwrap_err().raw_os_error(), Some(16)); // EBUSY, unstable API
+    let writers: Vec<_> = (0..4)
+        .map(|i| {
+            thread::spawn(move || loop {
+                let mut file = match open_nonblocking_result() {
+                    Ok(file) => file,
+                    Err(error) => return error,
+                };
+                match write_str(&mut file, &format!("Writer {i}")) {
+                    Err(error) if error.raw_os_error() == Some(16) => {} // EBUSY, unstable API
+                    result => result.unwrap(),
+                }
+                match read_str(&mut file) {
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => {}
+                    result => {
+                        result.unwrap();
+                    }
+                }
+                drop(file);
+                thread::sleep(Duration::from_millis(1));
+            })
+        })
+        .collect();
 
-        for _ in 0..MAX_MESSAGES {
-            assert_eq!(read_str(&mut file).unwrap(), line);
-        }
-        assert_eq!(
-            read_str(&mut file).unwrap_err().kind(),
-            io::ErrorKind::WouldBlock
-        );
+    thread::sleep(Duration::from_millis(100));
+    let start = Instant::now();
+    let mut backoff = Duration::from_millis(1);
+    while !rmmod() {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(Duration::from_millis(100));
     }
 
-    // Queue size = 0
-    for _ in 0..(MAX_MESSAGES / 2) {
-        write_str(&mut file, &line).unwrap();
-    }
-    // Queue size = MAX_MESSAGES / 2
-    for _ in 0..(MAX_MESSAGES / 2 - 1) {
-        assert_eq!(read_str(&mut file).unwrap(), line);
-    }
-    // Queue size = 1
-    for _ in 0..(MAX_MESSAGES / 2) {
-        write_str(&mut file, &line).unwrap();
-    }
-    // Queue size = MAX_MESSAGES / 2 + 1
-    for _ in 0..(MAX_MESSAGES / 2 + 1) {
-        assert_eq!(read_str(&mut file).unwrap(), line);
+    for writer in writers {
+        let error = writer.join().unwrap();
+        assert_eq!(error.raw_os_error(), Some(libc::ENXIO), "{error}");
     }
-    assert_eq!(
-        read_str(&mut file).unwrap_err().kind(),
-        io::ErrorKind::WouldBlock
-    );
 }
 
 #[test]
-fn test_empty_after_reading() {
-    let mut file = open();
-    write_str(&mut file, "Hello, World!").unwrap();
-    assert_eq!(read_str(&mut file).unwrap(), "Hello, World!");
-    assert_eq!(
-        read_str(&mut file).unwrap_err().kind(),
-        io::ErrorKind::WouldBlock
-    );
+fn test_info() {
+    let mut file = open_nonblocking();
+    let info = info(&mut file).unwrap();
+    assert_ne!(info.abi_version, 0);
+    assert!(info.max_messages_ceiling >= MAX_MESSAGES);
+    assert!(info.max_len_ceiling >= MAX_STRING_LENGTH);
+    // The current limits are within the ceilings
+    assert!(max_messages(&mut file).unwrap() <= info.max_messages_ceiling);
+    assert!(max_string_length(&mut file).unwrap() <= info.max_len_ceiling);
 }
 
 #[test]
-fn test_write_read_invalid_utf_8() {
-    let mut file = open();
-    let bytes = vec![0xC0];
-    write_bytes(&mut file, &bytes).unwrap();
-    assert_eq!(read_bytes(&mut file).unwrap(), bytes);
+fn test_drain_on_close() {
+    let mut file = open_nonblocking();
+    set_drain_on_close(&mut file, true).unwrap();
+    write_str(&mut file, "a").unwrap();
+    write_str(&mut file, "b").unwrap();
+    drop(file);
+
+    let mut file = open_nonblocking();
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+    assert_eq!(bytes_queued(&mut file).unwrap(), 0);
+    set_drain_on_close(&mut file, false).unwrap();
 }
 
 #[test]
-fn test_write_read_bytes_null() {
-    let mut file = open();
-    let bytes = vec![0xC0, 0x00, 0xC1];
-    write_bytes(&mut file, &bytes).unwrap();
-    assert_eq!(read_bytes(&mut file).unwrap(), bytes);
+fn test_no_drain_on_close() {
+    let mut file = open_nonblocking();
+    write_str(&mut file, "a").unwrap();
+    write_str(&mut file, "b").unwrap();
+    drop(file);
+
+    let mut file = open_nonblocking();
+    assert_eq!(queue_len(&mut file).unwrap(), 2);
+    assert_eq!(read_str(&mut file).unwrap(), "a");
+    assert_eq!(read_str(&mut file).unwrap(), "b");
 }
 
 #[test]
-fn test_open_thread() {
-    let mut file = open();
+fn test_write_front() {
+    let mut file = open_nonblocking();
+    write_str(&mut file, "a").unwrap();
+    write_str(&mut file, "b").unwrap();
+    assert_eq!(write_front(&mut file, b"urgent").unwrap(), 6);
+    assert_eq!(read_str(&mut file).unwrap(), "urgent");
+    assert_eq!(read_str(&mut file).unwrap(), "a");
+    assert_eq!(read_str(&mut file).unwrap(), "b");
 
-    let handles = (0..10)
-        .map(|thread_number| {
-            thread::spawn(move || {
-                let mut file = open();
-                thread::sleep(Duration::from_millis(thread_number * 100));
-                write_str(
-                    &mut file,
-                    &format!("Hello, World from thread {thread_number}!"),
-                )
-                .unwrap();
-                assert_eq!(
-                    read_str(&mut file).unwrap(),
-                    format!("Hello, World from thread {thread_number}!"),
-                );
-            })
-        })
-        .collect::<Vec<_>>();
-    for handle in handles {
-        handle.join().unwrap();
+    // The same limits as `write` apply
+    let max_string_length = max_string_length(&mut file).unwrap() as usize;
+    assert_eq!(
+        write_front(&mut file, &vec![b'A'; max_string_length + 1])
+            .unwrap_err()
+            .kind(),
+        io::ErrorKind::InvalidInput
+    );
+    let max_messages = max_messages(&mut file).unwrap();
+    for i in 0..max_messages {
+        write_str(&mut file, &format!("Write {i}")).unwrap();
     }
+    assert_eq!(
+        write_front(&mut file, b"urgent")
+            .unwrap_err()
+            .raw_os_error(),
+        Some(16) // EBUSY, unstable API
+    );
+    assert_eq!(read_str(&mut file).unwrap(), "Write 0");
+    flush(&mut file).unwrap();
+}
 
-    for thread_number in 0..10 {
-        write_str(
-            &mut file,
-            &format!("Hello, World from thread {thread_number}!"),
-        )
-        .unwrap();
-    }
-    let handles = (0..10)
-        .map(|thread_number| {
-            thread::spawn(move || {
-                let mut file = open();
-                thread::sleep(Duration::from_millis(thread_number * 100));
-                assert_eq!(
-                    read_str(&mut file).unwrap(),
-                    format!("Hello, World from thread {thread_number}!"),
-                );
-            })
-        })
-        .collect::<Vec<_>>();
-    for handle in handles {
-        handle.join().unwrap();
-    }
+#[test]
+fn test_rewind() {
+    let (mut a, mut b) = open_subscribers();
+    assert_eq!(
+        rewind(&mut a).unwrap_err().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    set_broadcast(&mut a, true).unwrap();
 
+    write_str(&mut a, "Test").unwrap();
+    assert_eq!(read_str(&mut a).unwrap(), "Test");
+    rewind(&mut a).unwrap();
+    assert!(poll_readable(&a, 0));
+    assert_eq!(read_str(&mut a).unwrap(), "Test");
+
+    // Once every subscriber has read it, it's gone
+    assert_eq!(read_str(&mut b).unwrap(), "Test");
+    assert_eq!(queue_len(&mut a).unwrap(), 0);
+    rewind(&mut a).unwrap();
     assert_eq!(
-        read_str(&mut file).unwrap_err().kind(),
+        read_str(&mut a).unwrap_err().kind(),
         io::ErrorKind::WouldBlock
     );
+
+    set_broadcast(&mut a, false).unwrap();
 }
 
 #[test]
-fn test_threads_spam() {
-    let mut file = open();
+fn test_byte_budget_accounting_under_load() {
+    let mut file = open_nonblocking();
+    set_byte_budget(&mut file, 64 * 1024).unwrap();
 
-    let handles = (0..16)
-        .map(|_| {
-            thread::spawn(|| {
-                let mut file = open();
-                for _ in 0..10 {
-                    for _ in 0..5 {
-                        write_str(&mut file, "Hello, World!").unwrap();
+    let writers = (0..8u64)
+        .map(|thread_number| {
+            thread::spawn(move || {
+                let mut file = open_nonblocking();
+                // xorshift, seeded differently for each thread
+                let mut state = 0x9E37_79B9_7F4A_7C15 ^ (thread_number + 1);
+                for _ in 0..200 {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    let message = vec![b'A'; (state % 1024) as usize + 1];
+                    match write_bytes(&mut file, &message) {
+                        Err(error) if error.raw_os_error() == Some(16) => {} // EBUSY, unstable API
+                        result => result.unwrap(),
                     }
-                    for _ in 0..5 {
-                        assert_eq!(read_str(&mut file).unwrap(), "Hello, World!");
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    // Half of the readers read in parts, which only removes a message with its last part
+    let readers = (0..8)
+        .map(|thread_number| {
+            thread::spawn(move || {
+                let mut file = open_nonblocking();
+                let stream = thread_number % 2 == 0;
+                set_stream_mode(&mut file, stream).unwrap();
+                let mut buf = vec![0; if stream { 100 } else { 1024 }];
+                for _ in 0..200 {
+                    match file.read(&mut buf) {
+                        Err(error) if error.kind() == io::ErrorKind::WouldBlock => {}
+                        result => {
+                            result.unwrap();
+                        }
                     }
                 }
             })
         })
         .collect::<Vec<_>>();
-    for handle in handles {
+    for handle in writers.into_iter().chain(readers) {
         handle.join().unwrap();
     }
 
+    while read_bytes(&mut file).is_ok() {}
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
+    assert_eq!(bytes_queued(&mut file).unwrap(), 0);
+    set_byte_budget(&mut file, 0).unwrap();
+}
+
+#[test]
+fn test_pop_if_eq() {
+    let mut file = open_nonblocking();
+    write_str(&mut file, "go").unwrap();
+    assert_eq!(
+        pop_if_eq(&mut file, b"stop").unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+    // A prefix of the message doesn't match either
+    assert_eq!(
+        pop_if_eq(&mut file, b"g").unwrap_err().kind(),
+        io::ErrorKind::WouldBlock
+    );
+    assert_eq!(queue_len(&mut file).unwrap(), 1);
+    assert_eq!(peek_str(&mut file).unwrap(), "go");
+
+    pop_if_eq(&mut file, b"go").unwrap();
+    assert_eq!(queue_len(&mut file).unwrap(), 0);
     assert_eq!(
-        read_str(&mut file).unwrap_err().kind(),
+        pop_if_eq(&mut file, b"go").unwrap_err().kind(),
         io::ErrorKind::WouldBlock
     );
 }